@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use mac_address::MacAddress;
+use serde::{Deserialize, Serialize};
+
+// Requests are written out in batches, this often, off the DHCP path
+const SAVE_INTERVAL_SECS: u64 = 10;
+// The clients kept; past this the one seen longest ago makes room
+const MAX_CLIENTS: usize = 10000;
+
+/// What a request says about the client that sent it
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ClientDetails {
+    pub mac: Option<MacAddress>,
+    /// Client system architecture, option 93
+    pub arch: Option<u16>,
+    /// UNDI version, option 94, as major.minor
+    pub undi: Option<String>,
+    pub vendor_class: Option<String>,
+    pub user_class: Option<String>,
+    /// Option 55, and the kind of client it and the rest of the request
+    /// say the client is
+    pub fingerprint: Option<String>,
+    pub client_type: Option<String>,
+    /// Machine UUID, option 97
+    pub uuid: Option<String>,
+    /// The relay agent the request came through, and the circuit and
+    /// remote ids it put in option 82
+    pub relay: Option<Ipv4Addr>,
+    pub circuit_id: Option<String>,
+    pub remote_id: Option<String>,
+}
+
+/// A client as it was last seen
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClientRecord {
+    #[serde(flatten)]
+    pub details: ClientDetails,
+    /// Seconds since the Unix epoch
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// Requests by message type
+    pub requests: BTreeMap<String, u64>,
+}
+
+impl ClientRecord {
+    pub fn total_requests(&self) -> u64 {
+        self.requests.values().sum()
+    }
+}
+
+/// The clients that have asked for an address, by client identifier,
+/// written to the clients file when there is one.
+#[derive(Default)]
+pub struct ClientInventory {
+    path: Option<String>,
+    clients: Mutex<BTreeMap<String, ClientRecord>>,
+    dirty: AtomicBool,
+}
+
+impl ClientInventory {
+    pub fn open(path: Option<&str>) -> Result<ClientInventory, Error> {
+        let clients = match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).
+                    map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+                Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e)
+            },
+            None => BTreeMap::new()
+        };
+        Ok(ClientInventory {
+            path: path.map(|path| path.to_string()),
+            clients: Mutex::new(clients),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the clients out if a request has come in since the last time
+    pub fn flush(&self) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let text = serde_json::to_string_pretty(&*self.clients.lock().unwrap()).
+            map_err(Error::other)?;
+        let temp = format!("{}.tmp", path);
+        let written = fs::write(&temp, text).and_then(|_| fs::rename(&temp, path));
+        if written.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        written
+    }
+
+    /// Flushes the inventory every few seconds, for good
+    pub fn save_periodically(&self) {
+        loop {
+            thread::sleep(Duration::from_secs(SAVE_INTERVAL_SECS));
+            if let Err(e) = self.flush() {
+                println!("cannot save client inventory: {}", e);
+            }
+        }
+    }
+
+    /// Records a request of the given message type
+    pub fn observe(&self, client: &str, details: ClientDetails, message: &str,
+                   now: u64) -> ClientRecord {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(client) {
+            let oldest = clients.iter().min_by_key(|(_, record)| record.last_seen).
+                map(|(client, _)| client.clone());
+            if let Some(oldest) = oldest {
+                clients.remove(&oldest);
+            }
+        }
+        let record = clients.entry(client.to_string()).or_insert_with(|| ClientRecord {
+            details: details.clone(),
+            first_seen: now,
+            last_seen: now,
+            requests: BTreeMap::new(),
+        });
+        record.details = details;
+        record.last_seen = now;
+        *record.requests.entry(message.to_string()).or_insert(0) += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+        record.clone()
+    }
+
+    pub fn get(&self, client: &str) -> Option<ClientRecord> {
+        self.clients.lock().unwrap().get(client).cloned()
+    }
+
+    /// All clients, or those whose requests came through one relay agent
+    pub fn clients(&self, relay: Option<Ipv4Addr>) -> BTreeMap<String, ClientRecord> {
+        self.clients.lock().unwrap().iter().
+            filter(|(_, record)| relay.is_none() || record.details.relay == relay).
+            map(|(client, record)| (client.clone(), record.clone())).collect()
+    }
+}
+
+/// The clients as a table, one line each
+pub fn table(clients: &BTreeMap<String, ClientRecord>) -> String {
+    let text = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+    let mut lines = vec![format!("{:<24} {:<17} {:<15} {:>4} {:<22} {:<32} {:>10} {:>10} {:>8}",
+                                 "CLIENT", "MAC", "RELAY", "ARCH", "TYPE", "VENDOR CLASS",
+                                 "FIRST SEEN", "LAST SEEN", "REQUESTS")];
+    for (client, record) in clients {
+        let details = &record.details;
+        lines.push(format!("{:<24} {:<17} {:<15} {:>4} {:<22} {:<32} {:>10} {:>10} {:>8}",
+                           client,
+                           text(&details.mac.map(|mac| mac.to_string().to_lowercase())),
+                           text(&details.relay.map(|relay| relay.to_string())),
+                           text(&details.arch.map(|arch| arch.to_string())),
+                           text(&details.client_type),
+                           text(&details.vendor_class),
+                           record.first_seen, record.last_seen, record.total_requests()));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(relay: Option<Ipv4Addr>) -> ClientDetails {
+        ClientDetails {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            arch: Some(7),
+            relay,
+            ..ClientDetails::default()
+        }
+    }
+
+    #[test]
+    fn test_observe() {
+        let inventory = ClientInventory::default();
+        inventory.observe("01:52:54:00:94:9e:f2", details(None), "discover", 10);
+        inventory.observe("01:52:54:00:94:9e:f2", details(None), "request", 20);
+        let relay = Some(Ipv4Addr::new(10, 0, 1, 1));
+        let record = inventory.observe("01:52:54:00:94:9e:f2", details(relay), "request", 30);
+        assert_eq!((10, 30), (record.first_seen, record.last_seen));
+        assert_eq!(Some(&2), record.requests.get("request"));
+        assert_eq!(3, record.total_requests());
+        assert_eq!(relay, record.details.relay);
+
+        inventory.observe("01:52:54:00:00:00:01", details(None), "bootp", 40);
+        assert_eq!(2, inventory.clients(None).len());
+        assert_eq!(vec!["01:52:54:00:94:9e:f2"],
+                   inventory.clients(relay).into_keys().collect::<Vec<_>>());
+        let table = table(&inventory.clients(relay));
+        assert!(table.lines().nth(1).unwrap().contains("10.0.1.1"), "{}", table);
+    }
+
+    #[test]
+    fn test_oldest_evicted() {
+        let inventory = ClientInventory::default();
+        for n in 0..MAX_CLIENTS as u64 {
+            inventory.observe(&format!("client {}", n), details(None), "discover", 100 + n);
+        }
+        inventory.observe("client 0", details(None), "request", 200000);
+        inventory.observe("client new", details(None), "discover", 200001);
+        assert_eq!(MAX_CLIENTS, inventory.clients(None).len());
+        assert!(inventory.get("client 1").is_none());
+        assert!(inventory.get("client 0").is_some());
+        assert!(inventory.get("client new").is_some());
+    }
+
+    #[test]
+    fn test_persisted() {
+        let path = std::env::temp_dir().
+            join(format!("rustboot-clients-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let inventory = ClientInventory::open(Some(path)).unwrap();
+        inventory.observe("01:52:54:00:94:9e:f2", details(None), "discover", 10);
+        assert!(ClientInventory::open(Some(path)).unwrap().get("01:52:54:00:94:9e:f2").is_none());
+        inventory.flush().unwrap();
+        let reopened = ClientInventory::open(Some(path)).unwrap();
+        assert_eq!(Some(7), reopened.get("01:52:54:00:94:9e:f2").unwrap().details.arch);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,915 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+use mac_address::MacAddress;
+use serde::{Deserialize, Serialize};
+
+use crate::state::BootState;
+
+/// The contents of the rustboot config file.  Every value has a default,
+/// so an empty file (or no file at all) gives the lab setup.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server_ip: Ipv4Addr,
+    /// Lets BOOTP clients without a reserved address take one from the
+    /// pool.  The address is theirs for good, as BOOTP has no leases.
+    pub bootp_dynamic: bool,
+    /// Lease times in seconds.  A client may ask for any lease time
+    /// between the minimum and the maximum, and gets the default if it
+    /// does not ask.  Subnets and hosts can override each of them.
+    pub min_lease_time: u32,
+    pub default_lease_time: u32,
+    pub max_lease_time: u32,
+    /// Answer a DISCOVER carrying option 80 with an ACK (RFC 4039)
+    pub rapid_commit: bool,
+    /// Stay silent to clients that are not network booting
+    pub pxe_only: bool,
+    /// MAC addresses or prefixes of them, such as OUIs.  When the allow
+    /// list is not empty, nothing else is answered.  Deny wins.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub domain_search: Vec<String>,
+    pub http_boot: HttpBootConfig,
+    pub ipxe: IpxeConfig,
+    pub dhcp6: Dhcp6Config,
+    pub subnets: Vec<SubnetConfig>,
+    pub hosts: Vec<HostConfig>,
+    pub classes: Vec<ClassConfig>,
+    pub rules: Vec<RuleConfig>,
+    /// Options sent to every client.  Subnets, classes and hosts can
+    /// override them code by code, in that order.
+    pub options: Vec<OptionConfig>,
+    /// Where hosts that are not in this file are looked up
+    pub provider: ProviderConfig,
+    /// Where the boot states of machines are kept across restarts
+    pub state_file: Option<String>,
+    /// Where the inventory of clients that have asked for an address is
+    /// kept across restarts
+    pub clients_file: Option<String>,
+    /// Fingerprints of kinds of clients, tried before the bundled ones
+    pub fingerprint_file: Option<String>,
+    /// What a machine boots in each boot state
+    pub states: StatesConfig,
+    /// The boot state a machine moves to when its installer reports
+    /// reaching a stage.  By default "done" means installed.
+    pub stage_states: BTreeMap<String, BootState>,
+    pub provision: ProvisionConfig,
+    pub tokens: TokensConfig,
+    /// The bearer token the management API under /api/ requires.  Without
+    /// one the API is off.
+    pub admin_token: Option<String>,
+    pub discovery: DiscoveryConfig,
+}
+
+/// Boots machines without a host entry into an image that reports their
+/// hardware, so that an operator can approve them into hosts.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Put unknown machines in the discover state
+    pub enabled: bool,
+    /// The discovery image, booted by the built in discover script.  It
+    /// is given the URL to post its facts to as rustboot.facts_url.
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub kernel_args: String,
+}
+
+/// Signed, time limited tokens in the URLs a machine is given, so that
+/// one machine cannot fetch the installer config or secrets of another.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokensConfig {
+    /// Put tokens in boot and installer URLs and refuse requests for
+    /// them without a valid one
+    pub enabled: bool,
+    /// How long a token is good for, in seconds
+    pub lifetime_secs: u64,
+    /// Bind the tokens given out over HTTP to the requester's address too
+    pub bind_ip: bool,
+    /// A file holding the signing key.  Without one a key is generated at
+    /// start, and tokens are no good after a restart.
+    pub key_file: Option<String>,
+}
+
+/// Settings for the installer configs rendered for each machine
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisionConfig {
+    /// The directory of templates, each named after what it renders:
+    /// kickstart, preseed, autoinstall, user-data or meta-data.  Other
+    /// files in it can be included.
+    pub template_dir: Option<String>,
+    /// Variables for every machine.  A host's own vars override them.
+    pub vars: BTreeMap<String, serde_json::Value>,
+}
+
+/// A boot profile for each boot state.  A state without one boots as
+/// if the machine had no state, except that localboot makes the iPXE
+/// script exit to the next boot device.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatesConfig {
+    pub discover: StateProfile,
+    pub install: StateProfile,
+    pub localboot: StateProfile,
+    pub rescue: StateProfile,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateProfile {
+    /// Sent to clients that are not running iPXE
+    pub boot_file: Option<String>,
+    /// The iPXE script template, in place of the usual one
+    pub template: Option<String>,
+}
+
+impl StatesConfig {
+    pub fn profile(&self, state: BootState) -> &StateProfile {
+        match state {
+            BootState::Discover => &self.discover,
+            BootState::Install => &self.install,
+            BootState::Localboot => &self.localboot,
+            BootState::Rescue => &self.rescue,
+        }
+    }
+}
+
+/// A backend for host entries, asked after the hosts of the config file.
+/// Its hosts have to reserve addresses outside the dynamic pools.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ProviderConfig {
+    /// Only the hosts of the config file
+    #[default]
+    Config,
+    /// A TOML file of [[hosts]], read again when it changes
+    File { path: String },
+    /// A SQLite database with a hosts table
+    Sqlite { path: String },
+    /// An inventory service answering GET <url>/hosts with a host as JSON
+    Http {
+        url: String,
+        #[serde(default = "default_inventory_timeout")]
+        timeout_secs: u64,
+    },
+}
+
+fn default_inventory_timeout() -> u64 {
+    2
+}
+
+/// A DHCP option that rustboot does not model, given with exactly one
+/// typed value or as raw hex.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OptionConfig {
+    pub code: u8,
+    #[serde(default)]
+    pub ip: Option<Vec<Ipv4Addr>>,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub byte: Option<u8>,
+    #[serde(default)]
+    pub uint16: Option<u16>,
+    #[serde(default)]
+    pub uint32: Option<u32>,
+    #[serde(default)]
+    pub int32: Option<i32>,
+    #[serde(default)]
+    pub boolean: Option<bool>,
+    #[serde(default)]
+    pub hex: Option<String>,
+}
+
+/// A group of machines that share options, an address pool and a boot
+/// profile.  Its boot values override the subnet's, and a host's
+/// override its classes'.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClassConfig {
+    pub name: String,
+    #[serde(default)]
+    pub options: Vec<OptionConfig>,
+    #[serde(default)]
+    pub pool_start: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub pool_end: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub next_server: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub tftp_server_name: Option<String>,
+    #[serde(default)]
+    pub boot_file: Option<String>,
+}
+
+/// Puts the clients that match every condition given into classes.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub classes: Vec<String>,
+    /// A prefix of the vendor class (option 60)
+    #[serde(default)]
+    pub vendor_class: Option<String>,
+    /// The user class (option 77)
+    #[serde(default)]
+    pub user_class: Option<String>,
+    /// The client architecture (option 93)
+    #[serde(default)]
+    pub arch: Option<u16>,
+    /// The first bytes of the MAC address, in hex
+    #[serde(default)]
+    pub oui: Option<String>,
+    /// The relay agent circuit ID (option 82), as text or hex
+    #[serde(default)]
+    pub circuit_id: Option<String>,
+    /// Option 55, as a comma separated list of codes
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// The kind of client its fingerprint says it is, such as "iPXE"
+    #[serde(default)]
+    pub client_type: Option<String>,
+}
+
+/// Settings for UEFI HTTP Boot clients, which expect a full URL
+/// rather than a path relative to the TFTP server.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpBootConfig {
+    pub base_url: String,
+    pub boot_file: String,
+}
+
+/// Settings for clients that are already running iPXE.  They are
+/// sent to rustboot's HTTP server for a script rendered for their MAC.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpxeConfig {
+    pub listen: SocketAddr,
+    pub base_url: String,
+    /// Offered instead of base_url to iPXE builds that support HTTPS
+    pub https_base_url: Option<String>,
+    /// A template to use in place of the built in boot script
+    pub template: Option<String>,
+}
+
+/// Settings for the DHCPv6 server.  It only runs if enabled.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dhcp6Config {
+    pub enabled: bool,
+    pub pool_start: Ipv6Addr,
+    pub pool_end: Ipv6Addr,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+    pub dns_servers: Vec<Ipv6Addr>,
+    /// RFC 5970 boot file URL for clients that are neither iPXE nor
+    /// UEFI HTTP Boot
+    pub boot_file_url: Option<String>,
+    pub boot_file_params: Vec<String>,
+}
+
+/// An IPv4 network, either in CIDR notation or as a bare address whose
+/// netmask is given on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Ipv4Network {
+    pub addr: Ipv4Addr,
+    pub prefix: Option<u8>,
+}
+
+impl TryFrom<String> for Ipv4Network {
+    type Error = &'static str;
+
+    fn try_from(text: String) -> Result<Ipv4Network, &'static str> {
+        let mut parts = text.splitn(2, '/');
+        let addr = parts.next().unwrap_or("").parse().map_err(|_| "bad IPv4 network")?;
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= 32 => Some(prefix),
+                _ => return Err("bad IPv4 prefix length")
+            },
+            None => None
+        };
+        Ok(Ipv4Network { addr, prefix })
+    }
+}
+
+impl Ipv4Network {
+    pub fn mask(&self) -> Option<Ipv4Addr> {
+        self.prefix.map(prefix_mask)
+    }
+}
+
+/// The netmask of a prefix length of up to 32 bits.
+pub fn prefix_mask(prefix: u8) -> Ipv4Addr {
+    match prefix {
+        0 => Ipv4Addr::new(0, 0, 0, 0),
+        _ => Ipv4Addr::from(u32::MAX << (32 - prefix.min(32) as u32)),
+    }
+}
+
+/// A classless static route (RFC 3442)
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub destination: Ipv4Network,
+    pub gateway: Ipv4Addr,
+}
+
+/// Settings for the machines on one network.  The boot server values
+/// let boot files come from a host other than the DHCP server.  The
+/// network may be given as "10.1.0.0/16", or with a separate netmask.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubnetConfig {
+    pub network: Ipv4Network,
+    #[serde(default)]
+    pub netmask: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub router: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub pool_start: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub pool_end: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub next_server: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub tftp_server_name: Option<String>,
+    #[serde(default)]
+    pub boot_file: Option<String>,
+    #[serde(default)]
+    pub domain_search: Option<Vec<String>>,
+    #[serde(default)]
+    pub min_lease_time: Option<u32>,
+    #[serde(default)]
+    pub default_lease_time: Option<u32>,
+    #[serde(default)]
+    pub max_lease_time: Option<u32>,
+    #[serde(default)]
+    pub rapid_commit: Option<bool>,
+    #[serde(default)]
+    pub options: Vec<OptionConfig>,
+}
+
+impl SubnetConfig {
+    /// The netmask, given or derived from the prefix length
+    pub fn mask(&self) -> Ipv4Addr {
+        self.netmask.or_else(|| self.network.mask()).
+            unwrap_or_else(|| Ipv4Addr::new(255, 255, 255, 255))
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::from(self.mask());
+        u32::from(addr) & mask == u32::from(self.network.addr) & mask
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network.addr) | !u32::from(self.mask()))
+    }
+}
+
+/// A machine that rustboot knows about, and what it should boot.  The
+/// boot server values override those of its subnet.  A host is matched
+/// by its MAC address, or by client identifier on hardware without one.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    #[serde(default)]
+    pub mac: Option<MacAddress>,
+    /// The DHCPv4 client identifier (option 61), in hex
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// The SMBIOS UUID the machine sends in option 97, so that it is the
+    /// same host whichever NIC it boots from
+    #[serde(default)]
+    pub uuid: Option<String>,
+    /// When set, only this NIC of the machine gets an answer
+    #[serde(default)]
+    pub boot_nic: Option<MacAddress>,
+    /// The DHCPv6 client DUID, in hex
+    #[serde(default)]
+    pub duid: Option<String>,
+    /// A reserved IPv4 address
+    #[serde(default)]
+    pub ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub min_lease_time: Option<u32>,
+    #[serde(default)]
+    pub default_lease_time: Option<u32>,
+    #[serde(default)]
+    pub max_lease_time: Option<u32>,
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub next_server: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub tftp_server_name: Option<String>,
+    #[serde(default)]
+    pub boot_file: Option<String>,
+    #[serde(default)]
+    pub kernel: Option<String>,
+    #[serde(default)]
+    pub initrd: Option<String>,
+    #[serde(default)]
+    pub kernel_args: String,
+    /// Chained in order if the kernel cannot be booted
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    /// The boot state of a host the state file has nothing on
+    #[serde(default)]
+    pub state: Option<BootState>,
+    /// The secret the host's installer sends with its callbacks
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Variables for the host's installer config templates
+    #[serde(default)]
+    pub vars: BTreeMap<String, serde_json::Value>,
+    /// Secrets, such as a root password hash, that the installer can
+    /// fetch once each
+    #[serde(default, skip_serializing)]
+    pub secrets: BTreeMap<String, String>,
+    /// Names of the classes the host belongs to
+    #[serde(default)]
+    pub classes: Vec<String>,
+    #[serde(default)]
+    pub options: Vec<OptionConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            server_ip: Ipv4Addr::new(192, 168, 144, 1),
+            bootp_dynamic: false,
+            min_lease_time: 60,
+            default_lease_time: 86400,
+            max_lease_time: 7 * 86400,
+            rapid_commit: false,
+            pxe_only: false,
+            allow: vec![],
+            deny: vec![],
+            domain_search: vec!["younglogic.net".to_string()],
+            http_boot: HttpBootConfig::default(),
+            ipxe: IpxeConfig::default(),
+            dhcp6: Dhcp6Config::default(),
+            subnets: vec![],
+            hosts: vec![],
+            classes: vec![],
+            rules: vec![],
+            options: vec![],
+            provider: ProviderConfig::default(),
+            state_file: None,
+            clients_file: None,
+            fingerprint_file: None,
+            states: StatesConfig::default(),
+            stage_states: BTreeMap::from([("done".to_string(), BootState::Localboot)]),
+            provision: ProvisionConfig::default(),
+            tokens: TokensConfig::default(),
+            admin_token: None,
+            discovery: DiscoveryConfig::default(),
+        }
+    }
+}
+
+impl Default for TokensConfig {
+    fn default() -> TokensConfig {
+        TokensConfig {
+            enabled: false,
+            lifetime_secs: 3600,
+            bind_ip: false,
+            key_file: None,
+        }
+    }
+}
+
+impl Default for HttpBootConfig {
+    fn default() -> HttpBootConfig {
+        HttpBootConfig {
+            base_url: "http://192.168.144.1/".to_string(),
+            boot_file: "efi/bootx64.efi".to_string(),
+        }
+    }
+}
+
+impl Default for IpxeConfig {
+    fn default() -> IpxeConfig {
+        IpxeConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            base_url: "http://192.168.144.1:8080/".to_string(),
+            https_base_url: None,
+            template: None,
+        }
+    }
+}
+
+impl Default for Dhcp6Config {
+    fn default() -> Dhcp6Config {
+        Dhcp6Config {
+            enabled: false,
+            pool_start: Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x100),
+            pool_end: Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x1ff),
+            preferred_lifetime: 3600,
+            valid_lifetime: 7200,
+            dns_servers: vec![],
+            boot_file_url: None,
+            boot_file_params: vec![],
+        }
+    }
+}
+
+impl IpxeConfig {
+    pub fn script_url(&self, mac: &MacAddress) -> String {
+        join_url(&self.base_url, &format!("ipxe/{}", mac_path(mac)))
+    }
+
+    pub fn https_script_url(&self, mac: &MacAddress) -> Option<String> {
+        self.https_base_url.as_ref().map(
+            |base_url| join_url(base_url, &format!("ipxe/{}", mac_path(mac))))
+    }
+
+    /// Where the machine's installer reports its progress, with the stage
+    /// appended
+    pub fn callback_url(&self, mac: &MacAddress) -> String {
+        join_url(&self.base_url, &format!("callback/{}", mac_path(mac)))
+    }
+
+    pub fn facts_url(&self, mac: &MacAddress) -> String {
+        join_url(&self.base_url, &format!("discovery/{}", mac_path(mac)))
+    }
+
+    pub fn secret_url(&self, mac: &MacAddress, name: &str) -> String {
+        join_url(&self.base_url, &format!("secret/{}/{}", mac_path(mac), name))
+    }
+}
+
+/// The form a MAC address takes in the URLs rustboot serves.
+pub fn mac_path(mac: &MacAddress) -> String {
+    mac.to_string().to_lowercase()
+}
+
+impl HttpBootConfig {
+    pub fn url(&self) -> String {
+        join_url(&self.base_url, &self.boot_file)
+    }
+}
+
+/// Parses hex digits, optionally separated by ':' or '-'.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, &'static str> {
+    if !text.bytes().all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'-') {
+        return Err("bad hex digit");
+    }
+    let digits: Vec<u8> = text.bytes().filter(|b| *b != b':' && *b != b'-').collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err("bad hex length");
+    }
+    let value = |digit: u8| (digit as char).to_digit(16).unwrap_or(0) as u8;
+    Ok(digits.chunks(2).map(|pair| value(pair[0]) << 4 | value(pair[1])).collect())
+}
+
+impl OptionConfig {
+    /// The option data in wire format
+    pub fn encode(&self) -> Result<Vec<u8>, &'static str> {
+        let mut values: Vec<Vec<u8>> = vec![];
+        if let Some(ips) = &self.ip {
+            values.push(ips.iter().flat_map(|ip| ip.octets().to_vec()).collect());
+        }
+        if let Some(text) = &self.text {
+            values.push(text.as_bytes().to_vec());
+        }
+        if let Some(byte) = self.byte {
+            values.push(vec![byte]);
+        }
+        if let Some(value) = self.uint16 {
+            values.push(value.to_be_bytes().to_vec());
+        }
+        if let Some(value) = self.uint32 {
+            values.push(value.to_be_bytes().to_vec());
+        }
+        if let Some(value) = self.int32 {
+            values.push(value.to_be_bytes().to_vec());
+        }
+        if let Some(value) = self.boolean {
+            values.push(vec![value as u8]);
+        }
+        if let Some(hex) = &self.hex {
+            values.push(parse_hex(hex)?);
+        }
+        if values.len() != 1 {
+            return Err("an option needs exactly one value");
+        }
+        let data = values.remove(0);
+        if data.len() > u8::MAX as usize {
+            return Err("option value too long");
+        }
+        Ok(data)
+    }
+}
+
+/// The dynamic pool when no subnets are configured
+pub const DEFAULT_POOL: (Ipv4Addr, Ipv4Addr) =
+    (Ipv4Addr::new(192, 168, 144, 100), Ipv4Addr::new(192, 168, 144, 200));
+
+// Pad, End, the message type and the server identifier belong to the
+// protocol, not to the site.
+const RESERVED_OPTION_CODES: [u8; 4] = [0, 53, 54, 255];
+
+fn check_options(options: &[OptionConfig]) -> Result<(), &'static str> {
+    for option in options {
+        if RESERVED_OPTION_CODES.contains(&option.code) {
+            return Err("option code cannot be configured");
+        }
+        option.encode()?;
+    }
+    Ok(())
+}
+
+/// Parses a UUID in its usual 8-4-4-4-12 hex form.
+pub fn parse_uuid(text: &str) -> Result<[u8; 16], &'static str> {
+    let bytes = parse_hex(text)?;
+    if bytes.len() != 16 {
+        return Err("a UUID is 16 bytes");
+    }
+    let mut uuid = [0; 16];
+    uuid.copy_from_slice(&bytes);
+    Ok(uuid)
+}
+
+/// Formats a UUID in its usual 8-4-4-4-12 hex form.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Joins a base URL and a relative path with exactly one '/' between them.
+pub fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+impl HostConfig {
+    /// Checks what can be checked without the rest of the config: the
+    /// host can be matched, and its identifiers and options parse.
+    pub fn check(&self) -> Result<(), &'static str> {
+        check_options(&self.options)?;
+        if self.mac.is_none() && self.client_id.is_none() && self.duid.is_none()
+            && self.uuid.is_none() {
+            return Err("host needs a mac, client_id, uuid or duid");
+        }
+        if let Some(uuid) = &self.uuid {
+            parse_uuid(uuid)?;
+        }
+        if let Some(client_id) = &self.client_id {
+            parse_hex(client_id)?;
+        }
+        let url_safe = |name: &String| !name.is_empty() &&
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !self.secrets.keys().all(url_safe) {
+            return Err("secret names are letters, digits, '-' and '_'");
+        }
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    pub fn parse(text: &str) -> Result<ServerConfig, Error> {
+        let config: ServerConfig = toml::from_str(text).
+            map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let invalid = |e| Error::new(ErrorKind::InvalidData, e);
+        check_options(&config.options).map_err(invalid)?;
+        for entry in config.allow.iter().chain(&config.deny) {
+            if parse_hex(entry).map_or(true, |prefix| prefix.len() > 6) {
+                return Err(invalid("bad MAC address or prefix in allow or deny list"));
+            }
+        }
+        for class in &config.classes {
+            check_options(&class.options).map_err(invalid)?;
+        }
+        let known_class = |name: &String| config.classes.iter().any(|class| class.name == *name);
+        for rule in &config.rules {
+            if !rule.classes.iter().all(known_class) {
+                return Err(invalid("rule names an unknown class"));
+            }
+            if let Some(oui) = &rule.oui {
+                if parse_hex(oui).map_or(true, |oui| oui.len() > 6) {
+                    return Err(invalid("bad oui in rule"));
+                }
+            }
+        }
+        for subnet in &config.subnets {
+            check_options(&subnet.options).map_err(invalid)?;
+            if subnet.netmask.is_none() && subnet.network.prefix.is_none() {
+                return Err(invalid("subnet needs a prefix length or a netmask"));
+            }
+            if subnet.routes.iter().any(|route| route.destination.prefix.is_none()) {
+                return Err(invalid("route destination needs a prefix length"));
+            }
+        }
+        for host in &config.hosts {
+            host.check().map_err(invalid)?;
+            if !host.classes.iter().all(known_class) {
+                return Err(invalid("host is in an unknown class"));
+            }
+        }
+        if let ProviderConfig::Http { url, .. } = &config.provider {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(invalid("inventory url must be http or https"));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(filename: &str) -> Result<ServerConfig, Error> {
+        ServerConfig::parse(&fs::read_to_string(filename)?)
+    }
+
+    /// The boot state of a machine that has none recorded: its host's,
+    /// or discover for a machine without a host when discovery is on
+    pub fn configured_state(&self, host: Option<&HostConfig>) -> Option<BootState> {
+        match host {
+            Some(host) => host.state,
+            None if self.discovery.enabled => Some(BootState::Discover),
+            None => None
+        }
+    }
+
+    pub fn subnet(&self, addr: Ipv4Addr) -> Option<&SubnetConfig> {
+        self.subnets.iter().find(|subnet| subnet.contains(addr))
+    }
+
+    /// Whether a host reserves an address in one of the dynamic pools.
+    /// The pools only leave out the hosts of this file, so a host from a
+    /// provider or from discovery has to reserve an address outside them.
+    pub fn reserves_pool_address(&self, host: &HostConfig) -> bool {
+        let mut pools: Vec<(Ipv4Addr, Ipv4Addr)> = self.subnets.iter()
+            .filter_map(|subnet| subnet.pool_start.zip(subnet.pool_end))
+            .chain(self.classes.iter().filter_map(|class| class.pool_start.zip(class.pool_end)))
+            .collect();
+        if self.subnets.is_empty() {
+            pools.push(DEFAULT_POOL);
+        }
+        let in_pool = host.ip.is_some_and(|ip| {
+            pools.iter().any(|(start, end)| *start <= ip && ip <= *end)
+        });
+        let in_pool6 = host.ipv6.is_some_and(|ip| {
+            self.dhcp6.enabled && self.dhcp6.pool_start <= ip && ip <= self.dhcp6.pool_end
+        });
+        in_pool || in_pool6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{find_host, HostKey};
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config = ServerConfig::parse("").unwrap();
+        assert_eq!(Ipv4Addr::new(192, 168, 144, 1), config.server_ip);
+        assert_eq!("http://192.168.144.1/efi/bootx64.efi", config.http_boot.url());
+    }
+
+    #[test]
+    fn test_parse_http_boot() {
+        let config = ServerConfig::parse(
+            "server_ip = \"10.0.0.1\"\n\
+             [http_boot]\n\
+             base_url = \"http://10.0.0.2:8080/images/\"\n\
+             boot_file = \"/shim.efi\"\n").unwrap();
+        assert_eq!(Ipv4Addr::new(10, 0, 0, 1), config.server_ip);
+        assert_eq!("http://10.0.0.2:8080/images/shim.efi", config.http_boot.url());
+    }
+
+    #[test]
+    fn test_parse_hosts() {
+        let config = ServerConfig::parse(
+            "[ipxe]\n\
+             base_url = \"http://10.0.0.2:8080\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9E:F2\"\n\
+             kernel = \"http://10.0.0.2/vmlinuz\"\n\
+             fallback = [\"http://10.0.0.2/rescue.ipxe\"]\n").unwrap();
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2]);
+        let key = |mac| HostKey { mac: Some(mac), ..HostKey::default() };
+        let host = find_host(&config.hosts, &key(mac)).unwrap();
+        assert_eq!(Some("http://10.0.0.2/vmlinuz".to_string()), host.kernel);
+        assert_eq!(None, host.initrd);
+        assert_eq!(1, host.fallback.len());
+        assert!(find_host(&config.hosts, &key(MacAddress::new([0; 6]))).is_none());
+        assert_eq!("http://10.0.0.2:8080/ipxe/52:54:00:94:9e:f2",
+                   config.ipxe.script_url(&mac));
+    }
+
+    #[test]
+    fn test_parse_subnets() {
+        let config = ServerConfig::parse(
+            "[[subnets]]\n\
+             network = \"10.1.0.0\"\n\
+             netmask = \"255.255.0.0\"\n\
+             next_server = \"10.1.0.5\"\n\
+             tftp_server_name = \"artifacts\"\n\
+             [[subnets]]\n\
+             network = \"10.2.0.0\"\n\
+             netmask = \"255.255.255.0\"\n").unwrap();
+        let subnet = config.subnet(Ipv4Addr::new(10, 1, 200, 3)).unwrap();
+        assert_eq!(Some(Ipv4Addr::new(10, 1, 0, 5)), subnet.next_server);
+        assert_eq!(Some("artifacts".to_string()), subnet.tftp_server_name);
+        assert_eq!(None, subnet.boot_file);
+        let subnet = config.subnet(Ipv4Addr::new(10, 2, 0, 9)).unwrap();
+        assert_eq!(None, subnet.next_server);
+        assert!(config.subnet(Ipv4Addr::new(10, 2, 1, 9)).is_none());
+    }
+
+    #[test]
+    fn test_parse_cidr_subnet() {
+        let config = ServerConfig::parse(
+            "[[subnets]]\n\
+             network = \"10.1.0.0/20\"\n\
+             router = \"10.1.0.1\"\n\
+             routes = [{ destination = \"10.9.0.0/16\", gateway = \"10.1.0.254\" }]\n").unwrap();
+        let subnet = config.subnet(Ipv4Addr::new(10, 1, 15, 3)).unwrap();
+        assert_eq!(Ipv4Addr::new(255, 255, 240, 0), subnet.mask());
+        assert_eq!(Ipv4Addr::new(10, 1, 15, 255), subnet.broadcast());
+        assert_eq!(Some(16), subnet.routes[0].destination.prefix);
+        assert!(config.subnet(Ipv4Addr::new(10, 1, 16, 3)).is_none());
+
+        assert!(ServerConfig::parse(
+            "[[subnets]]\nnetwork = \"10.1.0.0\"\n").is_err());
+        assert!(ServerConfig::parse(
+            "[[subnets]]\nnetwork = \"10.1.0.0/33\"\n").is_err());
+        assert!(ServerConfig::parse(
+            "[[subnets]]\nnetwork = \"10.1.0.0/16\"\n\
+             routes = [{ destination = \"10.9.0.0\", gateway = \"10.1.0.254\" }]\n").is_err());
+        assert_eq!(Ipv4Addr::new(0, 0, 0, 0), prefix_mask(0));
+        assert_eq!(Ipv4Addr::new(255, 255, 255, 255), prefix_mask(32));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let config = ServerConfig::parse(
+            "options = [{ code = 42, ip = [\"10.0.0.1\", \"10.0.0.2\"] },\n\
+                        { code = 26, uint16 = 9000 },\n\
+                        { code = 100, text = \"EST5EDT\" },\n\
+                        { code = 224, hex = \"de:ad:be:ef\" }]\n\
+             [[classes]]\n\
+             name = \"lab\"\n\
+             options = [{ code = 19, boolean = false }]\n").unwrap();
+        let encoded: Vec<Vec<u8>> = config.options.iter().map(|option| option.encode().unwrap()).collect();
+        assert_eq!(vec![vec![10, 0, 0, 1, 10, 0, 0, 2], vec![0x23, 0x28],
+                        b"EST5EDT".to_vec(), vec![0xde, 0xad, 0xbe, 0xef]], encoded);
+        assert_eq!(vec![0], config.classes[0].options[0].encode().unwrap());
+
+        assert!(ServerConfig::parse("options = [{ code = 42 }]").is_err());
+        assert!(ServerConfig::parse("options = [{ code = 42, byte = 1, text = \"x\" }]").is_err());
+        assert!(ServerConfig::parse("options = [{ code = 53, byte = 1 }]").is_err());
+        assert!(ServerConfig::parse("options = [{ code = 224, hex = \"xyz\" }]").is_err());
+        assert!(ServerConfig::parse(
+            "[[hosts]]\nmac = \"52:54:00:94:9e:f2\"\nclasses = [\"nope\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_allow_and_deny() {
+        let config = ServerConfig::parse(
+            "pxe_only = true\n\
+             allow = [\"52:54:00\", \"00:1a:4b:00:00:01\"]\n").unwrap();
+        assert!(config.pxe_only);
+        assert_eq!(2, config.allow.len());
+        assert!(ServerConfig::parse("deny = [\"52:54:00:00:00:00:01\"]").is_err());
+        assert!(ServerConfig::parse("deny = [\"laptop\"]").is_err());
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(Ok(vec![0x52, 0x54, 0x0a]), parse_hex("52:54-0A"));
+        assert_eq!(Err("bad hex length"), parse_hex("525"));
+        assert_eq!(Err("bad hex digit"), parse_hex("aéb"));
+        assert_eq!(Err("bad hex digit"), parse_hex("+a"));
+        assert!(ServerConfig::parse("allow = [\"52:é4\"]").is_err());
+    }
+
+    #[test]
+    fn test_unknown_field_rejected() {
+        assert!(ServerConfig::parse("no_such_field = 1").is_err());
+    }
+
+    #[test]
+    fn test_host_needs_an_identity() {
+        assert!(ServerConfig::parse("[[hosts]]\nhostname = \"x\"\n").is_err());
+        assert!(ServerConfig::parse("[[hosts]]\nclient_id = \"xyz\"\n").is_err());
+        assert!(ServerConfig::parse("[[hosts]]\nclient_id = \"ff:00:01\"\n").is_ok());
+        assert!(ServerConfig::parse("[[hosts]]\nuuid = \"4c23b200-e138-adc3\"\n").is_err());
+        assert!(ServerConfig::parse(
+            "[[hosts]]\nuuid = \"4c23b200-e138-adc3-45b7-97d2dd220e1b\"\n").is_ok());
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use std::net::Ipv4Addr;
+
+use mac_address::MacAddress;
+
+use crate::clients::ClientDetails;
+use crate::config::format_uuid;
+use crate::config::parse_hex;
+use crate::config::ClassConfig;
+use crate::config::HostConfig;
+use crate::config::RuleConfig;
+use crate::config::ServerConfig;
+
+use super::fingerprint::FingerprintDb;
+use super::packet::ClientId;
+use super::packet::DHCPOptionCode;
+use super::packet::DHCPPacket;
+use super::packet::VendorData;
+use super::pxe;
+
+// Sub-options of the Relay Agent Information option (RFC 3046)
+const AGENT_CIRCUIT_ID: u8 = 1;
+const AGENT_REMOTE_ID: u8 = 2;
+
+/// The parts of a request that classification rules can match on
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientFacts {
+    pub vendor_class: Option<String>,
+    pub user_class: Option<String>,
+    pub arch: Option<u16>,
+    pub mac: Option<MacAddress>,
+    pub circuit_id: Option<Vec<u8>>,
+    pub fingerprint: Option<String>,
+    /// The kind of client its fingerprint says it is
+    pub client_type: Option<String>,
+}
+
+impl ClientFacts {
+    pub fn from_request(packet: &DHCPPacket, options: &HashMap::<DHCPOptionCode, VendorData>,
+                        fingerprints: &FingerprintDb) -> ClientFacts {
+        let text = |code| options.get(&code).
+            map(|option: &VendorData| String::from_utf8_lossy(&option.data).to_string());
+        let mut facts = ClientFacts {
+            vendor_class: text(DHCPOptionCode::VendorClassIdentifier),
+            user_class: text(DHCPOptionCode::UserClassInfo),
+            arch: match options.get(&DHCPOptionCode::ClientSystemArchitectureType) {
+                Some(option) if option.data.len() >= 2 =>
+                    Some(u16::from_be_bytes([option.data[0], option.data[1]])),
+                _ => None
+            },
+            mac: ClientId::from_packet(packet, options).mac().
+                or_else(|| if packet._hw_addr_len == 6 { Some(packet.client_mac()) } else { None }),
+            circuit_id: options.get(&DHCPOptionCode::RelayAgentInformation).
+                and_then(|option| circuit_id(&option.data)),
+            fingerprint: options.get(&DHCPOptionCode::ParameterRequestList).
+                map(|option| fingerprint(&option.data)),
+            client_type: None,
+        };
+        facts.client_type = fingerprints.identify(&facts, &packet.option_codes()).
+            map(|name| name.to_string());
+        facts
+    }
+}
+
+/// Option 55 as the comma separated list of codes the rules match on
+pub fn fingerprint(parameter_request_list: &[u8]) -> String {
+    let codes: Vec<String> = parameter_request_list.iter().map(|code| code.to_string()).collect();
+    codes.join(",")
+}
+
+fn agent_sub_option(relay_agent_information: &[u8], wanted: u8) -> Option<Vec<u8>> {
+    let mut offset = 0;
+    while offset + 2 <= relay_agent_information.len() {
+        let code = relay_agent_information[offset];
+        let len = relay_agent_information[offset + 1] as usize;
+        let value = relay_agent_information.get(offset + 2..offset + 2 + len)?;
+        if code == wanted {
+            return Some(value.to_vec());
+        }
+        offset += 2 + len;
+    }
+    None
+}
+
+fn circuit_id(relay_agent_information: &[u8]) -> Option<Vec<u8>> {
+    agent_sub_option(relay_agent_information, AGENT_CIRCUIT_ID)
+}
+
+/// Printable ASCII as it is, anything else as hex
+fn printable(bytes: &[u8]) -> String {
+    if !bytes.is_empty() && bytes.iter().all(|byte| byte.is_ascii_graphic() || *byte == b' ') {
+        String::from_utf8_lossy(bytes).to_string()
+    } else {
+        let octets: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        octets.join(":")
+    }
+}
+
+/// What the client inventory keeps of a request
+pub fn client_details(packet: &DHCPPacket, options: &HashMap::<DHCPOptionCode, VendorData>,
+                      facts: &ClientFacts) -> ClientDetails {
+    let relay_agent = options.get(&DHCPOptionCode::RelayAgentInformation);
+    let gateway_ip = Ipv4Addr::from(packet._gateway_ip);
+    ClientDetails {
+        mac: facts.mac,
+        arch: facts.arch,
+        undi: match options.get(&DHCPOptionCode::ClientNetworkInterfaceIdentifier) {
+            Some(option) if option.data.len() == 3 && option.data[0] == 1 =>
+                Some(format!("{}.{}", option.data[1], option.data[2])),
+            _ => None
+        },
+        vendor_class: facts.vendor_class.clone(),
+        user_class: facts.user_class.clone(),
+        fingerprint: facts.fingerprint.clone(),
+        client_type: facts.client_type.clone(),
+        uuid: pxe::machine_uuid(options).map(|uuid| format_uuid(&uuid)),
+        relay: if gateway_ip.is_unspecified() { None } else { Some(gateway_ip) },
+        circuit_id: facts.circuit_id.as_deref().map(printable),
+        remote_id: relay_agent.and_then(|option| agent_sub_option(&option.data, AGENT_REMOTE_ID)).
+            map(|remote_id| printable(&remote_id)),
+    }
+}
+
+/// A rule matches when every condition it sets holds.
+pub fn matches(rule: &RuleConfig, facts: &ClientFacts) -> bool {
+    if let Some(prefix) = &rule.vendor_class {
+        if !facts.vendor_class.as_ref().is_some_and(|class| class.starts_with(prefix.as_str())) {
+            return false;
+        }
+    }
+    if rule.user_class.is_some() && rule.user_class != facts.user_class {
+        return false;
+    }
+    if rule.arch.is_some() && rule.arch != facts.arch {
+        return false;
+    }
+    if let Some(oui) = &rule.oui {
+        let oui = parse_hex(oui).unwrap_or_default();
+        if !facts.mac.is_some_and(|mac| mac.bytes().starts_with(&oui)) {
+            return false;
+        }
+    }
+    if let Some(expected) = &rule.circuit_id {
+        let matched = match &facts.circuit_id {
+            Some(circuit_id) => circuit_id == expected.as_bytes() ||
+                parse_hex(expected).as_ref() == Ok(circuit_id),
+            None => false
+        };
+        if !matched {
+            return false;
+        }
+    }
+    if rule.fingerprint.is_some() && rule.fingerprint != facts.fingerprint {
+        return false;
+    }
+    if rule.client_type.is_some() && rule.client_type != facts.client_type {
+        return false;
+    }
+    true
+}
+
+/// The classes a client is in: those its host entry names, and those of
+/// every rule it matches, in the order the classes are configured.
+pub fn classify<'a>(config: &'a ServerConfig, host: Option<&HostConfig>,
+                    facts: &ClientFacts) -> Vec<&'a ClassConfig> {
+    let mut names: Vec<&str> = match host {
+        Some(host) => host.classes.iter().map(|name| name.as_str()).collect(),
+        None => vec![]
+    };
+    for rule in config.rules.iter().filter(|rule| matches(rule, facts)) {
+        names.extend(rule.classes.iter().map(|name| name.as_str()));
+    }
+    config.classes.iter().filter(|class| names.contains(&class.name.as_str())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(text: &str) -> RuleConfig {
+        let config = ServerConfig::parse(&format!(
+            "[[classes]]\nname = \"x\"\n[[rules]]\nclasses = [\"x\"]\n{}\n", text)).unwrap();
+        config.rules[0].clone()
+    }
+
+    fn facts() -> ClientFacts {
+        ClientFacts {
+            vendor_class: Some("PXEClient:Arch:00007:UNDI:003016".to_string()),
+            user_class: Some("iPXE".to_string()),
+            arch: Some(7),
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            circuit_id: Some(b"eth1/1".to_vec()),
+            fingerprint: Some("1,3,6,67".to_string()),
+            client_type: Some("iPXE".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_rule_conditions() {
+        assert!(matches(&rule("vendor_class = \"PXEClient:Arch:00007\""), &facts()));
+        assert!(!matches(&rule("vendor_class = \"HTTPClient\""), &facts()));
+        assert!(matches(&rule("user_class = \"iPXE\"\narch = 7"), &facts()));
+        assert!(!matches(&rule("user_class = \"iPXE\"\narch = 0"), &facts()));
+        assert!(matches(&rule("oui = \"52:54:00\""), &facts()));
+        assert!(!matches(&rule("oui = \"00:1a:4b\""), &facts()));
+        assert!(matches(&rule("circuit_id = \"eth1/1\""), &facts()));
+        assert!(matches(&rule("circuit_id = \"657468312f31\""), &facts()));
+        assert!(matches(&rule("fingerprint = \"1,3,6,67\""), &facts()));
+        assert!(!matches(&rule("fingerprint = \"1,3,6\""), &facts()));
+        assert!(matches(&rule("client_type = \"iPXE\""), &facts()));
+        assert!(!matches(&rule("client_type = \"GRUB\""), &facts()));
+        assert!(!matches(&rule("arch = 7"), &ClientFacts::default()));
+    }
+
+    #[test]
+    fn test_circuit_id() {
+        assert_eq!(Some(b"ab".to_vec()), circuit_id(&[2, 1, 9, 1, 2, b'a', b'b']));
+        assert_eq!(None, circuit_id(&[2, 1, 9]));
+        assert_eq!(None, circuit_id(&[1, 5, b'a']));
+        assert_eq!(Some(vec![9]), agent_sub_option(&[1, 2, b'a', b'b', 2, 1, 9], AGENT_REMOTE_ID));
+    }
+
+    #[test]
+    fn test_printable() {
+        assert_eq!("eth1/1", printable(b"eth1/1"));
+        assert_eq!("00:1a:4b", printable(&[0, 0x1a, 0x4b]));
+    }
+}
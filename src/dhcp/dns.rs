@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+// Domain names in DHCP options use the DNS wire format from RFC 1035
+// section 3.1: a sequence of length prefixed labels ending in a zero
+// length label.  The Domain Search option (RFC 3397) also allows the
+// compression pointers of RFC 1035 section 4.1.4, with offsets counted
+// from the start of the option data.
+
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+const POINTER: u8 = 0xc0;
+const MAX_POINTER_OFFSET: usize = 0x3fff;
+
+/// Encodes a list of domain names, replacing any suffix that has already
+/// been written with a pointer to it.
+pub fn encode_domain_list(domains: &[String]) -> Result<Vec<u8>, &'static str> {
+    let mut buf: Vec<u8> = vec![];
+    let mut suffixes: HashMap<String, usize> = HashMap::new();
+    for domain in domains {
+        let domain = domain.trim_end_matches('.');
+        if domain.len() + 1 > MAX_NAME_LEN {
+            return Err("domain name too long");
+        }
+        let labels: Vec<&str> = domain.split('.').collect();
+        let mut compressed = false;
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(offset) = suffixes.get(&suffix) {
+                buf.push(POINTER | (offset >> 8) as u8);
+                buf.push((offset & 0xff) as u8);
+                compressed = true;
+                break;
+            }
+            let label = labels[i];
+            if label.is_empty() {
+                return Err("empty label in domain name");
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err("domain name label too long");
+            }
+            if buf.len() <= MAX_POINTER_OFFSET {
+                suffixes.insert(suffix, buf.len());
+            }
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        if !compressed {
+            buf.push(0);
+        }
+    }
+    Ok(buf)
+}
+
+/// Decodes a list of domain names, following compression pointers.  A
+/// pointer has to point back before the name that uses it, so a
+/// malicious list cannot make this loop.
+pub fn decode_domain_list(data: &[u8]) -> Result<Vec<String>, &'static str> {
+    let mut domains = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let (domain, next) = decode_name(data, offset)?;
+        domains.push(domain);
+        offset = next;
+    }
+    Ok(domains)
+}
+
+// Returns the name at offset and the offset just past it
+fn decode_name(data: &[u8], start: usize) -> Result<(String, usize), &'static str> {
+    let mut labels: Vec<String> = vec![];
+    let mut offset = start;
+    let mut limit = start;
+    let mut end = None;
+    let mut name_len = 0;
+    loop {
+        let len = *data.get(offset).ok_or("truncated domain name")? as usize;
+        if len == 0 {
+            if end.is_none() {
+                end = Some(offset + 1);
+            }
+            break;
+        }
+        if len as u8 & POINTER == POINTER {
+            let low = *data.get(offset + 1).ok_or("truncated domain name")? as usize;
+            let target = ((len & 0x3f) << 8) | low;
+            if target >= limit {
+                return Err("domain name pointer does not point backwards");
+            }
+            if end.is_none() {
+                end = Some(offset + 2);
+            }
+            limit = target;
+            offset = target;
+            continue;
+        }
+        if len > MAX_LABEL_LEN {
+            return Err("bad domain name label length");
+        }
+        let label = data.get(offset + 1..offset + 1 + len).ok_or("truncated domain name")?;
+        name_len += len + 1;
+        if name_len + 1 > MAX_NAME_LEN {
+            return Err("domain name too long");
+        }
+        labels.push(String::from_utf8_lossy(label).to_string());
+        offset += len + 1;
+    }
+    Ok((labels.join("."), end.unwrap_or(offset + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_encode_rfc3397_example() {
+        let encoded = encode_domain_list(
+            &domains(&["eng.apple.com.", "marketing.apple.com."])).unwrap();
+        let mut expected = vec![3];
+        expected.extend_from_slice(b"eng");
+        expected.push(5);
+        expected.extend_from_slice(b"apple");
+        expected.push(3);
+        expected.extend_from_slice(b"com");
+        expected.push(0);
+        expected.push(9);
+        expected.extend_from_slice(b"marketing");
+        expected.extend_from_slice(&[0xc0, 0x04]);
+        assert_eq!(expected, encoded);
+    }
+
+    #[test]
+    fn test_encode_single_domain() {
+        let encoded = encode_domain_list(&domains(&["younglogic.net"])).unwrap();
+        assert_eq!(b"\x0ayounglogic\x03net\x00".to_vec(), encoded);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let names = domains(&["lab.younglogic.net", "younglogic.net",
+                              "example.com", "www.example.com"]);
+        let encoded = encode_domain_list(&names).unwrap();
+        assert_eq!(names, decode_domain_list(&encoded).unwrap());
+    }
+
+    #[test]
+    fn test_bad_names() {
+        assert!(encode_domain_list(&domains(&["a..b"])).is_err());
+        assert!(encode_domain_list(&domains(&[&"x".repeat(64)])).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_loops_and_truncation() {
+        assert!(decode_domain_list(&[0xc0, 0x00]).is_err());
+        assert!(decode_domain_list(&[3, b'c', b'o', b'm', 0, 0xc0, 0x05]).is_err());
+        assert!(decode_domain_list(&[3, b'c', b'o']).is_err());
+        assert!(decode_domain_list(&[3, b'c', b'o', b'm']).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+// The encapsulated options iPXE sends in option 175.  There is no RFC for
+// these; the codes come from src/include/ipxe/dhcp.h and
+// src/include/ipxe/features.h in the iPXE tree.
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ::num_derive::FromPrimitive)]
+#[repr(u8)]
+#[num_traits = "num_traits"]
+pub enum IpxeFeature {
+    PxeExt = 0x10,
+    Iscsi = 0x11,
+    Aoe = 0x12,
+    Http = 0x13,
+    Https = 0x14,
+    Tftp = 0x15,
+    Ftp = 0x16,
+    Dns = 0x17,
+    BzImage = 0x18,
+    Multiboot = 0x19,
+    Slam = 0x1a,
+    Srp = 0x1b,
+    Nbi = 0x20,
+    Pxe = 0x21,
+    Elf = 0x22,
+    Comboot = 0x23,
+    Efi = 0x24,
+    Fcoe = 0x25,
+    Vlan = 0x26,
+    Menu = 0x27,
+    Sdi = 0x28,
+    Nfs = 0x29,
+}
+
+const PRIORITY: u8 = 0x01;
+const BUS_ID: u8 = 0xb1;
+const VERSION: u8 = 0xeb;
+
+/// What an iPXE build says about itself.  Sub-options that are not
+/// understood are skipped.
+#[derive(Debug, Default, PartialEq)]
+pub struct IpxeFeatures {
+    pub priority: Option<i8>,
+    pub bus_id: Option<Vec<u8>>,
+    pub version: Option<(u8, u8, u8)>,
+    pub features: Vec<IpxeFeature>,
+}
+
+impl IpxeFeatures {
+    pub fn parse(data: &[u8]) -> Result<IpxeFeatures, &'static str> {
+        let mut ipxe_features = IpxeFeatures::default();
+        let mut offset = 0;
+        while offset < data.len() {
+            let code = data[offset];
+            if code == 0 {
+                offset += 1;
+                continue;
+            }
+            if code == 0xff {
+                break;
+            }
+            if offset + 1 >= data.len() {
+                return Err("truncated iPXE option");
+            }
+            let len = data[offset + 1] as usize;
+            let start = offset + 2;
+            if start + len > data.len() {
+                return Err("truncated iPXE option");
+            }
+            let value = &data[start..start + len];
+            match code {
+                PRIORITY if len == 1 => ipxe_features.priority = Some(value[0] as i8),
+                BUS_ID => ipxe_features.bus_id = Some(value.to_vec()),
+                VERSION if len == 3 =>
+                    ipxe_features.version = Some((value[0], value[1], value[2])),
+                _ => if let Some(feature) = num::FromPrimitive::from_u8(code) {
+                    ipxe_features.features.push(feature)
+                }
+            }
+            offset = start + len;
+        }
+        Ok(ipxe_features)
+    }
+
+    pub fn has(&self, feature: IpxeFeature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Option 175 from boot-packet.bin, a BIOS build of iPXE 1.0.0
+    const FIXTURE: [u8; 48] = [177, 5, 1, 128, 134, 16, 14, 235, 3, 1, 0, 0,
+                               23, 1, 1, 34, 1, 1, 19, 1, 1, 17, 1, 1, 39, 1,
+                               1, 25, 1, 1, 16, 1, 2, 33, 1, 1, 21, 1, 1, 24,
+                               1, 1, 38, 1, 1, 18, 1, 1];
+
+    #[test]
+    fn test_parse_fixture() {
+        let ipxe_features = IpxeFeatures::parse(&FIXTURE).unwrap();
+        assert_eq!(Some((1, 0, 0)), ipxe_features.version);
+        assert_eq!(Some(vec![1, 128, 134, 16, 14]), ipxe_features.bus_id);
+        assert_eq!(None, ipxe_features.priority);
+        assert_eq!(12, ipxe_features.features.len());
+        assert!(ipxe_features.has(IpxeFeature::Http));
+        assert!(ipxe_features.has(IpxeFeature::Dns));
+        assert!(ipxe_features.has(IpxeFeature::BzImage));
+        assert!(ipxe_features.has(IpxeFeature::Menu));
+        assert!(!ipxe_features.has(IpxeFeature::Https));
+        assert!(!ipxe_features.has(IpxeFeature::Efi));
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        assert!(IpxeFeatures::parse(&FIXTURE[0..FIXTURE.len() - 1]).is_err());
+        assert!(IpxeFeatures::parse(&[0x13]).is_err());
+        assert_eq!(IpxeFeatures::default(), IpxeFeatures::parse(&[]).unwrap());
+    }
+}
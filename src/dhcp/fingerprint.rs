@@ -0,0 +1,179 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use serde::Deserialize;
+
+use super::classify::fingerprint;
+use super::classify::ClientFacts;
+
+const BUNDLED: &str = include_str!("../../data/fingerprints.toml");
+
+/// A kind of client and what its requests look like.  Every condition
+/// given has to hold.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fingerprint {
+    pub name: String,
+    /// Option 55, as a comma separated list of codes in the client's order
+    #[serde(default)]
+    pub parameter_request_list: Option<String>,
+    /// A prefix of the vendor class (option 60)
+    #[serde(default)]
+    pub vendor_class: Option<String>,
+    /// The user class (option 77)
+    #[serde(default)]
+    pub user_class: Option<String>,
+    /// Options the request has to carry
+    #[serde(default)]
+    pub options: Vec<u8>,
+    /// The codes of all options in the request, in order
+    #[serde(default)]
+    pub option_order: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FingerprintFile {
+    #[serde(default)]
+    fingerprints: Vec<Fingerprint>,
+}
+
+fn parse_codes(text: &str) -> Result<Vec<u8>, &'static str> {
+    text.split(',').map(|code| code.trim().parse().map_err(|_| "bad option code in fingerprint")).
+        collect()
+}
+
+impl Fingerprint {
+    fn check(&self) -> Result<(), &'static str> {
+        if self.name.is_empty() {
+            return Err("fingerprint needs a name");
+        }
+        if self.parameter_request_list.is_none() && self.vendor_class.is_none() &&
+            self.user_class.is_none() && self.options.is_empty() && self.option_order.is_none() {
+            return Err("fingerprint needs a condition");
+        }
+        for codes in self.parameter_request_list.iter().chain(&self.option_order) {
+            parse_codes(codes)?;
+        }
+        Ok(())
+    }
+
+    /// The lists of codes as the client facts give them, without spaces
+    fn normalize(&mut self) {
+        for codes in self.parameter_request_list.iter_mut().chain(&mut self.option_order) {
+            *codes = fingerprint(&parse_codes(codes).unwrap_or_default());
+        }
+    }
+
+    pub fn matches(&self, facts: &ClientFacts, option_codes: &[u8]) -> bool {
+        if self.parameter_request_list.is_some() &&
+            self.parameter_request_list != facts.fingerprint {
+            return false;
+        }
+        if let Some(prefix) = &self.vendor_class {
+            if !facts.vendor_class.as_ref().is_some_and(|class| class.starts_with(prefix)) {
+                return false;
+            }
+        }
+        if self.user_class.is_some() && self.user_class != facts.user_class {
+            return false;
+        }
+        if !self.options.iter().all(|code| option_codes.contains(code)) {
+            return false;
+        }
+        if self.option_order.is_some() && self.option_order != Some(fingerprint(option_codes)) {
+            return false;
+        }
+        true
+    }
+}
+
+fn parse(text: &str, source: &str) -> Result<Vec<Fingerprint>, Error> {
+    let invalid = |e: String| Error::new(ErrorKind::InvalidData, format!("{}: {}", source, e));
+    let file: FingerprintFile = toml::from_str(text).map_err(|e| invalid(e.to_string()))?;
+    let mut fingerprints = file.fingerprints;
+    for fingerprint in &mut fingerprints {
+        fingerprint.check().map_err(|e| invalid(e.to_string()))?;
+        fingerprint.normalize();
+    }
+    Ok(fingerprints)
+}
+
+/// The fingerprints of the client kinds that can be told apart: the
+/// user's first, then the bundled ones.
+pub struct FingerprintDb {
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl Default for FingerprintDb {
+    /// Just the bundled fingerprints
+    fn default() -> FingerprintDb {
+        FingerprintDb { fingerprints: parse(BUNDLED, "bundled fingerprints").unwrap() }
+    }
+}
+
+impl FingerprintDb {
+    pub fn load(path: Option<&str>) -> Result<FingerprintDb, Error> {
+        let mut fingerprints = match path {
+            Some(path) => parse(&fs::read_to_string(path)?, path)?,
+            None => vec![]
+        };
+        fingerprints.extend(FingerprintDb::default().fingerprints);
+        Ok(FingerprintDb { fingerprints })
+    }
+
+    /// The name of the first fingerprint the request matches
+    pub fn identify(&self, facts: &ClientFacts, option_codes: &[u8]) -> Option<&str> {
+        self.fingerprints.iter().find(|fingerprint| fingerprint.matches(facts, option_codes)).
+            map(|fingerprint| fingerprint.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(vendor_class: &str, fingerprint: &str) -> ClientFacts {
+        ClientFacts {
+            vendor_class: Some(vendor_class.to_string()).filter(|class| !class.is_empty()),
+            fingerprint: Some(fingerprint.to_string()),
+            ..ClientFacts::default()
+        }
+    }
+
+    #[test]
+    fn test_bundled() {
+        let db = FingerprintDb::default();
+        let pxe = facts("PXEClient:Arch:00000:UNDI:002001", "1,2,3,4,5,6,11,12,13,15,16,17,18");
+        assert_eq!(Some("BIOS PXE ROM"), db.identify(&pxe, &[53, 55, 60]));
+        assert_eq!(Some("iPXE"), db.identify(&pxe, &[53, 55, 60, 175]));
+        let efi = facts("PXEClient:Arch:00007:UNDI:003016", "1,2,3");
+        assert_eq!(Some("UEFI firmware"), db.identify(&efi, &[]));
+        let ovmf = facts("PXEClient:Arch:00007:UNDI:003000", "1,2,3");
+        assert_eq!(Some("UEFI firmware (EDK II)"), db.identify(&ovmf, &[]));
+        let dhclient = facts("", "1,28,2,3,15,6,119,12,44,47,26,121,42");
+        assert_eq!(Some("Linux dhclient"), db.identify(&dhclient, &[53, 55]));
+        assert_eq!(Some("Windows"), db.identify(&facts("MSFT 5.0", "1,3,6"), &[]));
+        assert_eq!(None, db.identify(&facts("", "1,3"), &[]));
+    }
+
+    #[test]
+    fn test_user_fingerprints_come_first() {
+        let path = std::env::temp_dir().
+            join(format!("rustboot-fingerprints-{}.toml", std::process::id()));
+        fs::write(&path, "[[fingerprints]]\n\
+                          name = \"Acme switch\"\n\
+                          parameter_request_list = \"1, 28, 2, 3, 15, 6, 119, 12, 44, 47, 26, 121, 42\"\n\
+                          option_order = \"53,61,55\"\n").unwrap();
+        let db = FingerprintDb::load(path.to_str()).unwrap();
+        let dhclient = facts("", "1,28,2,3,15,6,119,12,44,47,26,121,42");
+        assert_eq!(Some("Acme switch"), db.identify(&dhclient, &[53, 61, 55]));
+        assert_eq!(Some("Linux dhclient"), db.identify(&dhclient, &[53, 55, 61]));
+
+        fs::write(&path, "[[fingerprints]]\nname = \"nothing\"\n").unwrap();
+        assert!(FingerprintDb::load(path.to_str()).is_err());
+        fs::write(&path, "[[fingerprints]]\nname = \"x\"\noption_order = \"53,300\"\n").unwrap();
+        assert!(FingerprintDb::load(path.to_str()).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::packet::ClientId;
+
+// How long an address in an OFFER is held for the client to REQUEST it
+const OFFER_HOLD_SECS: u64 = 60;
+
+/// The addresses that can be handed out dynamically on a subnet.
+/// Addresses reserved for hosts are excluded.
+pub struct AddressPool {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+    pub excluded: HashSet<Ipv4Addr>,
+}
+
+impl AddressPool {
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(self.start) <= u32::from(addr) && u32::from(addr) <= u32::from(self.end)
+            && !self.excluded.contains(&addr)
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        (u32::from(self.start)..=u32::from(self.end)).map(Ipv4Addr::from)
+            .filter(move |addr| !self.excluded.contains(addr))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lease {
+    pub ip: Ipv4Addr,
+    /// None for BOOTP clients and others that never give an address back
+    pub expires: Option<SystemTime>,
+    /// False while the address is only offered
+    pub bound: bool,
+}
+
+impl Lease {
+    fn is_active(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires > now,
+            None => true
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LeaseManager {
+    leases: Mutex<HashMap<ClientId, Lease>>,
+}
+
+impl LeaseManager {
+    pub fn new() -> LeaseManager {
+        LeaseManager::default()
+    }
+
+    fn is_free(leases: &HashMap<ClientId, Lease>, client_id: &ClientId,
+               addr: Ipv4Addr, now: SystemTime) -> bool {
+        !leases.iter().any(|(owner, lease)| {
+            owner != client_id && lease.ip == addr && lease.is_active(now)
+        })
+    }
+
+    /// A reservation wins, then the address the client already has, then
+    /// the one it asked for, then the first free one in the pool.
+    fn choose(leases: &HashMap<ClientId, Lease>, client_id: &ClientId,
+              reserved: Option<Ipv4Addr>, requested: Option<Ipv4Addr>,
+              pool: Option<&AddressPool>, now: SystemTime) -> Option<Ipv4Addr> {
+        if reserved.is_some() {
+            return reserved;
+        }
+        let pool = pool?;
+        if let Some(lease) = leases.get(client_id) {
+            if lease.is_active(now) && pool.contains(lease.ip) {
+                return Some(lease.ip);
+            }
+        }
+        if let Some(requested) = requested {
+            if pool.contains(requested) && LeaseManager::is_free(leases, client_id, requested, now) {
+                return Some(requested);
+            }
+        }
+        let used: HashSet<Ipv4Addr> = leases.iter()
+            .filter(|(owner, lease)| *owner != client_id && lease.is_active(now))
+            .map(|(_, lease)| lease.ip)
+            .collect();
+        pool.addresses().find(|addr| !used.contains(addr))
+    }
+
+    /// Picks an address for a DISCOVER and holds it long enough for the
+    /// client to request it.  A bound lease is left as it is.
+    pub fn offer(&self, client_id: &ClientId, reserved: Option<Ipv4Addr>,
+                 requested: Option<Ipv4Addr>, pool: Option<&AddressPool>) -> Option<Ipv4Addr> {
+        let now = SystemTime::now();
+        let mut leases = self.leases.lock().unwrap();
+        let ip = LeaseManager::choose(&leases, client_id, reserved, requested, pool, now)?;
+        let still_bound = match leases.get(client_id) {
+            Some(lease) => lease.bound && lease.ip == ip && lease.is_active(now),
+            None => false
+        };
+        if !still_bound {
+            leases.insert(client_id.clone(), Lease {
+                ip,
+                expires: Some(now + Duration::from_secs(OFFER_HOLD_SECS)),
+                bound: false
+            });
+        }
+        Some(ip)
+    }
+
+    /// Binds an address to the client.  A lease time of None makes the
+    /// binding permanent, as BOOTP needs.  A client that asks for an
+    /// address gets that one or none, so that it can be sent a NAK.
+    pub fn commit(&self, client_id: &ClientId, reserved: Option<Ipv4Addr>,
+                  requested: Option<Ipv4Addr>, pool: Option<&AddressPool>,
+                  lease_time: Option<Duration>) -> Option<Ipv4Addr> {
+        let now = SystemTime::now();
+        let mut leases = self.leases.lock().unwrap();
+        let ip = LeaseManager::choose(&leases, client_id, reserved, requested, pool, now)?;
+        if requested.is_some_and(|requested| requested != ip) {
+            return None;
+        }
+        leases.insert(client_id.clone(), Lease {
+            ip,
+            expires: lease_time.map(|lease_time| now + lease_time),
+            bound: true
+        });
+        Some(ip)
+    }
+
+    pub fn lease(&self, client_id: &ClientId) -> Option<Lease> {
+        self.leases.lock().unwrap().get(client_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pool() -> AddressPool {
+        AddressPool {
+            start: Ipv4Addr::new(10, 0, 0, 10),
+            end: Ipv4Addr::new(10, 0, 0, 12),
+            excluded: HashSet::from([Ipv4Addr::new(10, 0, 0, 11)]),
+        }
+    }
+
+    fn client(last: u8) -> ClientId {
+        ClientId(vec![1, 0x52, 0x54, 0, 0, 0, last])
+    }
+
+    #[test]
+    fn test_offer_then_commit() {
+        let leases = LeaseManager::new();
+        let pool = test_pool();
+        let offered = leases.offer(&client(1), None, None, Some(&pool)).unwrap();
+        assert_eq!(Ipv4Addr::new(10, 0, 0, 10), offered);
+        assert!(!leases.lease(&client(1)).unwrap().bound);
+        let committed = leases.commit(&client(1), None, None, Some(&pool),
+                                      Some(Duration::from_secs(3600))).unwrap();
+        assert_eq!(offered, committed);
+        assert!(leases.lease(&client(1)).unwrap().bound);
+    }
+
+    #[test]
+    fn test_pool_skips_excluded_and_runs_out() {
+        let leases = LeaseManager::new();
+        let pool = test_pool();
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 10)),
+                   leases.offer(&client(1), None, None, Some(&pool)));
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 12)),
+                   leases.offer(&client(2), None, None, Some(&pool)));
+        assert_eq!(None, leases.offer(&client(3), None, None, Some(&pool)));
+        assert_eq!(None, leases.offer(&client(3), None, None, None));
+    }
+
+    #[test]
+    fn test_requested_and_reserved() {
+        let leases = LeaseManager::new();
+        let pool = test_pool();
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 12)),
+                   leases.offer(&client(1), None, Some(Ipv4Addr::new(10, 0, 0, 12)),
+                                Some(&pool)));
+        // Already offered to someone else
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 10)),
+                   leases.offer(&client(2), None, Some(Ipv4Addr::new(10, 0, 0, 12)),
+                                Some(&pool)));
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 11)),
+                   leases.commit(&client(3), Some(Ipv4Addr::new(10, 0, 0, 11)), None,
+                                 None, None));
+        assert_eq!(None, leases.lease(&client(3)).unwrap().expires);
+    }
+
+    #[test]
+    fn test_commit_refuses_other_address() {
+        let leases = LeaseManager::new();
+        let pool = test_pool();
+        let hour = Some(Duration::from_secs(3600));
+        leases.commit(&client(1), None, None, Some(&pool), hour).unwrap();
+        // Held by another client, and outside the pool
+        assert_eq!(None, leases.commit(&client(2), None, Some(Ipv4Addr::new(10, 0, 0, 10)),
+                                       Some(&pool), hour));
+        assert_eq!(None, leases.commit(&client(2), None, Some(Ipv4Addr::new(10, 0, 1, 10)),
+                                       Some(&pool), hour));
+        assert_eq!(None, leases.lease(&client(2)));
+    }
+
+    #[test]
+    fn test_expired_lease_is_reused() {
+        let leases = LeaseManager::new();
+        let pool = AddressPool {
+            start: Ipv4Addr::new(10, 0, 0, 10),
+            end: Ipv4Addr::new(10, 0, 0, 10),
+            excluded: HashSet::new(),
+        };
+        leases.commit(&client(1), None, None, Some(&pool), Some(Duration::from_secs(0)));
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 10)),
+                   leases.offer(&client(2), None, None, Some(&pool)));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::mem::transmute;
 use std::mem::size_of;
 use std::str::FromStr;
@@ -6,68 +7,313 @@ use std::net::Ipv4Addr;
 
 use std::net::SocketAddr;
 use std::net::UdpSocket;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::format;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::fs;
+use std::sync::Arc;
 use mac_address::MacAddress;
 
+use crate::clients::ClientInventory;
+use crate::config::ClassConfig;
+use crate::config::DEFAULT_POOL;
+use crate::config::HostConfig;
+use crate::config::OptionConfig;
+use crate::config::ServerConfig;
+use crate::config::StateProfile;
+use crate::config::SubnetConfig;
+use crate::provider;
+use crate::provider::HostKey;
+use crate::provider::HostProvider;
+use crate::state::BootState;
+use crate::state::StateStore;
+use crate::token;
+use crate::token::TokenSigner;
+
+mod classify;
+mod dns;
+mod etherboot;
+mod fingerprint;
+mod lease;
 mod packet;
+mod policy;
+mod pxe;
+mod routes;
+mod validate;
+use etherboot::IpxeFeature;
+use etherboot::IpxeFeatures;
+use fingerprint::FingerprintDb;
+use lease::AddressPool;
+use lease::LeaseManager;
+use packet::ClientId;
 use packet::DHCPOptCodes;
 use packet::DHCPOptionCode;
 use packet::DHCPMessageType;
 use packet::DHCPPacket;
 use packet::VENDOR_MAGIC;
 use packet::VendorData;
+use validate::PacketCounters;
 
 
 extern crate num;
 extern crate num_derive;
 
-pub struct MachineConfig{
-    pub mac_address: MacAddress
+pub struct MachineConfig<'a>{
+    pub client_id: ClientId,
+    pub mac_address: MacAddress,
+    config: &'a ServerConfig,
+    subnet: Option<&'a SubnetConfig>,
+    classes: Vec<&'a ClassConfig>,
+    host: Option<HostConfig>,
+    /// What the request says about the client
+    facts: classify::ClientFacts,
+    /// The boot state the next boot uses, if the machine has one
+    pub state: Option<BootState>,
+    /// Signs the machine's script URL when tokens are enabled
+    pub url_token: Option<String>,
 }
 
-impl MachineConfig{
+impl<'a> MachineConfig<'a>{
+
+    /// The settings for a machine outside of a DHCP exchange, such as
+    /// when it fetches its installer config.  It is on the subnet of its
+    /// reserved address, and in the classes its host entry names.
+    pub fn for_machine(config: &'a ServerConfig, mac: MacAddress, host: Option<HostConfig>,
+                       state: Option<BootState>) -> MachineConfig<'a> {
+        let facts = classify::ClientFacts { mac: Some(mac), ..classify::ClientFacts::default() };
+        let subnet_addr = host.as_ref().and_then(|host| host.ip).unwrap_or(config.server_ip);
+        let mut client_id = vec![1];
+        client_id.extend_from_slice(&mac.bytes());
+        MachineConfig{
+            client_id: ClientId(client_id),
+            mac_address: mac,
+            config,
+            subnet: config.subnet(subnet_addr),
+            classes: classify::classify(config, host.as_ref(), &facts),
+            host,
+            facts,
+            state,
+            url_token: None,
+        }
+    }
+
+    pub fn host(&self) -> Option<&HostConfig> {
+        self.host.as_ref()
+    }
 
     pub fn server_ip(&self) -> Ipv4Addr{
-        Ipv4Addr::new(192,168,144,1)
+        self.config.server_ip
     }
 
-    pub fn your_ip(&self) -> Ipv4Addr{
-        Ipv4Addr::new(192,168,144,100)
+    /// A host with a designated boot NIC is only answered on that NIC.
+    pub fn is_boot_nic(&self, mac: Option<MacAddress>) -> bool{
+        match self.host.as_ref().and_then(|host| host.boot_nic) {
+            Some(boot_nic) => mac == Some(boot_nic),
+            None => true
+        }
+    }
+
+    pub fn classes(&self) -> &[&'a ClassConfig]{
+        &self.classes
+    }
+
+    // The first of the client's classes that sets a value
+    fn class_setting<T>(&self, setting: impl Fn(&ClassConfig) -> Option<T>) -> Option<T> {
+        self.classes.iter().find_map(|class| setting(class))
+    }
+
+    /// The configured options, global first, then the subnet's, the
+    /// classes' and the host's, each overriding the code before it.
+    pub fn custom_options(&self) -> Result<Vec<VendorData>, &'static str>{
+        let mut scopes: Vec<&[OptionConfig]> = vec![&self.config.options];
+        if let Some(subnet) = self.subnet {
+            scopes.push(&subnet.options);
+        }
+        for class in self.classes() {
+            scopes.push(&class.options);
+        }
+        if let Some(host) = &self.host {
+            scopes.push(&host.options);
+        }
+        let mut merged: Vec<VendorData> = vec![];
+        for option in scopes.into_iter().flatten() {
+            let vendor_data = VendorData::raw(option.code, &option.encode()?)?;
+            match merged.iter_mut().find(|merged| merged.code == option.code) {
+                Some(existing) => *existing = vendor_data,
+                None => merged.push(vendor_data)
+            }
+        }
+        Ok(merged)
+    }
+
+    pub fn rapid_commit(&self) -> bool{
+        self.subnet.and_then(|subnet| subnet.rapid_commit).
+            unwrap_or(self.config.rapid_commit)
+    }
+
+    pub fn reserved_ip(&self) -> Option<Ipv4Addr>{
+        self.host.as_ref().and_then(|host| host.ip)
+    }
+
+    /// The dynamic pool of the client's class, else of its subnet.  With
+    /// no subnets configured at all, this is .100 to .200 of the lab
+    /// network.  The reservations of the config file's hosts are left
+    /// out; other hosts are kept out of the pools by the provider.
+    pub fn pool(&self) -> Option<AddressPool>{
+        let class_pool = self.class_setting(|class| class.pool_start.zip(class.pool_end));
+        let (start, end) = match (class_pool, self.subnet) {
+            (Some(class_pool), _) => class_pool,
+            (None, Some(subnet)) => (subnet.pool_start?, subnet.pool_end?),
+            (None, None) if self.config.subnets.is_empty() => DEFAULT_POOL,
+            (None, None) => return None
+        };
+        let excluded = self.config.hosts.iter().filter_map(|host| host.ip).collect();
+        Some(AddressPool{ start, end, excluded })
     }
 
     pub fn subnet_mask(&self) -> Ipv4Addr {
-        Ipv4Addr::new(255,255,255,0)
+        match self.subnet {
+            Some(subnet) => subnet.mask(),
+            None => Ipv4Addr::new(255,255,255,0)
+        }
+    }
+
+    pub fn broadcast_address(&self) -> Ipv4Addr {
+        match self.subnet {
+            Some(subnet) => subnet.broadcast(),
+            None => Ipv4Addr::from(u32::from(self.config.server_ip) |
+                                   !u32::from(self.subnet_mask()))
+        }
     }
 
     pub fn router(&self) -> Ipv4Addr {
-        Ipv4Addr::new(192,168,144,1)
+        self.subnet.and_then(|subnet| subnet.router).
+            unwrap_or(self.config.server_ip)
+    }
+
+    /// The subnet's static routes.  A client that gets option 121
+    /// ignores option 3, so the default route is added unless one is
+    /// configured.
+    pub fn classless_routes(&self) -> Vec<routes::Route> {
+        let mut routes: Vec<routes::Route> = match self.subnet {
+            Some(subnet) => subnet.routes.iter().map(|route| {
+                (route.destination.addr, route.destination.prefix.unwrap_or(32), route.gateway)
+            }).collect(),
+            None => vec![]
+        };
+        if !routes.is_empty() && !routes.iter().any(|(_, prefix, _)| *prefix == 0) {
+            routes.push((Ipv4Addr::new(0,0,0,0), 0, self.router()));
+        }
+        routes
+    }
+
+    // A lease time setting from the host, else the subnet, else the server
+    fn lease_setting(&self, host: fn(&HostConfig) -> Option<u32>,
+                     subnet: fn(&SubnetConfig) -> Option<u32>, server: u32) -> u32 {
+        self.host.as_ref().and_then(host).
+            or_else(|| self.subnet.and_then(subnet)).
+            unwrap_or(server)
     }
 
-    pub fn lease_time(&self) -> u32 {
-         86400
+    /// The lease time the client asked for, or the default, kept between
+    /// the minimum and the maximum.
+    pub fn lease_time(&self, requested: Option<u32>) -> u32 {
+        let min = self.lease_setting(|host| host.min_lease_time,
+                                     |subnet| subnet.min_lease_time,
+                                     self.config.min_lease_time);
+        let max = self.lease_setting(|host| host.max_lease_time,
+                                     |subnet| subnet.max_lease_time,
+                                     self.config.max_lease_time);
+        let default = self.lease_setting(|host| host.default_lease_time,
+                                         |subnet| subnet.default_lease_time,
+                                         self.config.default_lease_time);
+        requested.unwrap_or(default).min(max).max(min)
     }
 
+    /// The server identifier, option 54: the address clients are to
+    /// send their REQUESTs to
     pub fn dhcp_server(&self) -> Ipv4Addr {
-        Ipv4Addr::new(192,168,144,1)
+        self.server_ip()
+    }
+
+    /// The server the client fetches its boot file from: siaddr
+    pub fn next_server(&self) -> Ipv4Addr {
+        self.host.as_ref().and_then(|host| host.next_server)
+            .or_else(|| self.class_setting(|class| class.next_server))
+            .or_else(|| self.subnet.and_then(|subnet| subnet.next_server))
+            .unwrap_or_else(|| self.server_ip())
+    }
+
+    pub fn tftp_server_name(&self) -> Option<String> {
+        self.host.as_ref().and_then(|host| host.tftp_server_name.clone())
+            .or_else(|| self.class_setting(|class| class.tftp_server_name.clone()))
+            .or_else(|| self.subnet.and_then(|subnet| subnet.tftp_server_name.clone()))
+    }
+
+    /// The profile of the machine's boot state, if it has one
+    pub fn state_profile(&self) -> Option<&StateProfile> {
+        self.state.map(|state| self.config.states.profile(state))
     }
 
     pub fn boot_file_name(&self) -> String {
-        "pxelinux/pxelinux.0".to_string()
+        self.state_profile().and_then(|profile| profile.boot_file.clone())
+            .or_else(|| self.host.as_ref().and_then(|host| host.boot_file.clone()))
+            .or_else(|| self.class_setting(|class| class.boot_file.clone()))
+            .or_else(|| self.subnet.and_then(|subnet| subnet.boot_file.clone()))
+            .unwrap_or_else(|| "pxelinux/pxelinux.0".to_string())
     }
-    pub fn domain_search(&self) -> String {
-        "younglogic.net".to_string()
+
+    pub fn http_boot_url(&self) -> String {
+        self.config.http_boot.url()
     }
 
+    fn with_token(&self, url: String) -> String {
+        match &self.url_token {
+            Some(token) => format!("{}?token={}", url, token),
+            None => url
+        }
+    }
+
+    pub fn ipxe_script_url(&self) -> String {
+        self.with_token(self.config.ipxe.script_url(&self.mac_address))
+    }
+
+    pub fn ipxe_https_script_url(&self) -> Option<String> {
+        self.config.ipxe.https_script_url(&self.mac_address).map(|url| self.with_token(url))
+    }
+
+    /// Picks the boot file for an iPXE client based on the protocols its
+    /// build supports.  A build without HTTP support is sent the TFTP
+    /// boot file instead of a script URL.
+    pub fn ipxe_boot_file_name(&self, ipxe_features: &IpxeFeatures) -> String {
+        if ipxe_features.has(IpxeFeature::Https) {
+            if let Some(url) = self.ipxe_https_script_url() {
+                return url;
+            }
+        }
+        if ipxe_features.has(IpxeFeature::Http) {
+            self.ipxe_script_url()
+        } else {
+            self.boot_file_name()
+        }
+    }
+
+    pub fn domain_search(&self) -> Vec<String> {
+        match self.subnet.and_then(|subnet| subnet.domain_search.as_ref()) {
+            Some(domain_search) => domain_search.clone(),
+            None => self.config.domain_search.clone()
+        }
+    }
+
+
+    pub fn dns_addresses(&self) -> Vec<Ipv4Addr> {
+        vec![Ipv4Addr::new(75,75,75,75),Ipv4Addr::new(75,75,75,76),Ipv4Addr::new(8,8,8,8)]
+    }
 
     fn dns_servers(&self) -> Vec<u8> {
-        let _dns_servers : Vec<Ipv4Addr> = vec![
-            Ipv4Addr::new(75,75,75,75),Ipv4Addr::new(75,75,75,76),Ipv4Addr::new(8,8,8,8)]; 
         let mut addr_buf = vec![];
 
-        for addr in _dns_servers {
+        for addr in self.dns_addresses() {
             for b in addr.octets().to_vec() {
                 addr_buf.push(b)
             }
@@ -77,7 +323,104 @@ impl MachineConfig{
 
 }
 
+pub const HTTP_CLIENT: &str = "HTTPClient";
+
+/// UEFI HTTP Boot clients send a vendor class of the form
+/// "HTTPClient:Arch:xxxxx:UNDI:yyyzzz".
+pub fn is_http_boot_client(options: &HashMap::<DHCPOptionCode, VendorData>) -> bool {
+    match options.get(&DHCPOptionCode::VendorClassIdentifier){
+        Some(option) => option.data.starts_with(HTTP_CLIENT.as_bytes()),
+        None => false
+    }
+}
+
+/// True if the option is in the client's Parameter Request List.
+pub fn is_requested(options: &HashMap::<DHCPOptionCode, VendorData>,
+                    code: DHCPOptionCode) -> bool {
+    match options.get(&DHCPOptionCode::ParameterRequestList){
+        Some(option) => option.data.contains(&(code as u8)),
+        None => false
+    }
+}
+
+/// Merges the configured options into a reply, replacing any option
+/// the handler built with the same code.
+pub fn add_custom_options(config: &MachineConfig,
+                          vendor_data: &mut Vec::<VendorData>) -> Result<(), &'static str> {
+    for custom in config.custom_options()? {
+        match vendor_data.iter_mut().find(|option| option.code == custom.code) {
+            Some(existing) => *existing = custom,
+            None => vendor_data.push(custom)
+        }
+    }
+    Ok(())
+}
+
+/// The lease time a client asks for in option 51, if any
+pub fn requested_lease_time(options: &HashMap::<DHCPOptionCode, VendorData>) -> Option<u32> {
+    match options.get(&DHCPOptionCode::IPAddressLeaseTime){
+        Some(option) if option.data.len() == 4 =>
+            Some(u32::from_be_bytes([option.data[0], option.data[1],
+                                     option.data[2], option.data[3]])),
+        _ => None
+    }
+}
+
+/// Options 51, 58 and 59.  T1 and T2 are the RFC 2131 defaults of half
+/// and seven eighths of the lease.  An infinite lease never renews.
+pub fn lease_time_options(lease_time: u32) -> Result<Vec<VendorData>, &'static str> {
+    let (renewal, rebinding) = if lease_time == u32::MAX {
+        (u32::MAX, u32::MAX)
+    } else {
+        (lease_time / 2, (lease_time as u64 * 7 / 8) as u32)
+    };
+    Ok(vec![
+        VendorData::new(DHCPOptionCode::IPAddressLeaseTime, &lease_time.to_be_bytes())?,
+        VendorData::new(DHCPOptionCode::RenewalTime, &renewal.to_be_bytes())?,
+        VendorData::new(DHCPOptionCode::RebindingTime, &rebinding.to_be_bytes())?,
+    ])
+}
+
+/// The address a client asks for in option 50, if any
+pub fn requested_ip(options: &HashMap::<DHCPOptionCode, VendorData>) -> Option<Ipv4Addr> {
+    match options.get(&DHCPOptionCode::RequestedIPAddress){
+        Some(option) if option.data.len() == 4 =>
+            Some(Ipv4Addr::new(option.data[0], option.data[1],
+                               option.data[2], option.data[3])),
+        _ => None
+    }
+}
+
+/// The server a client accepted an offer from, option 54
+pub fn server_identifier(options: &HashMap::<DHCPOptionCode, VendorData>) -> Option<Ipv4Addr> {
+    match options.get(&DHCPOptionCode::DHCPServer){
+        Some(option) if option.data.len() == 4 =>
+            Some(Ipv4Addr::new(option.data[0], option.data[1],
+                               option.data[2], option.data[3])),
+        _ => None
+    }
+}
+
+pub const IPXE_USER_CLASS: &str = "iPXE";
+
+/// iPXE identifies itself with a user class of "iPXE".  It sends the bare
+/// string rather than the RFC 3004 length prefixed form.
+pub fn is_ipxe_client(options: &HashMap::<DHCPOptionCode, VendorData>) -> bool {
+    match options.get(&DHCPOptionCode::UserClassInfo){
+        Some(option) => option.data == IPXE_USER_CLASS.as_bytes(),
+        None => false
+    }
+}
+
 pub struct DHCPServer{
+    config: ServerConfig,
+    provider: Arc<dyn HostProvider>,
+    states: Arc<StateStore>,
+    tokens: Arc<TokenSigner>,
+    clients: Arc<ClientInventory>,
+    fingerprints: FingerprintDb,
+    leases: LeaseManager,
+    counters: PacketCounters,
     logging: bool,
     local_ipv4: IpAddr,
     capture: bool,
@@ -87,35 +430,114 @@ pub struct DHCPServer{
 
 impl DHCPServer{
 
-    pub fn machine_config(&self, mac: &MacAddress ) -> MachineConfig{
-        return MachineConfig{
-            mac_address: *mac
+    /// The host entry for a client, by machine UUID, client identifier
+    /// or the MAC address in it.  A provider that cannot be asked leaves
+    /// the client unknown.
+    fn host(&self, client_id: &ClientId, uuid: Option<&[u8; 16]>) -> Option<HostConfig> {
+        let key = HostKey {
+            mac: client_id.mac(),
+            client_id: Some(client_id.0.clone()),
+            uuids: uuid.map(|uuid| vec![*uuid, pxe::swap_uuid_fields(uuid)]).unwrap_or_default(),
+            ..HostKey::default()
+        };
+        match self.provider.lookup(&key) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("host lookup for {} failed: {}", client_id, e);
+                None
+            }
         }
     }
 
-    pub fn new(logging: bool, capture: bool, capture_dir: &str) -> Result <DHCPServer, Error>  {
+    /// Everything that goes into the reply to a request, worked out once
+    /// per packet
+    pub fn machine_config(&self, request_packet: &DHCPPacket,
+                          options: &HashMap::<DHCPOptionCode, VendorData>) -> MachineConfig<'_>{
+        let uuid = pxe::machine_uuid(options);
+        let mut client_id = ClientId::from_packet(request_packet, options);
+        let host = self.host(&client_id, uuid.as_ref());
+        // A machine matched by UUID keeps one lease whichever NIC asks
+        if let (Some(uuid), Some(HostConfig{ uuid: Some(_), .. })) = (uuid, host.as_ref()) {
+            let mut id = vec![0];
+            id.extend_from_slice(&uuid);
+            client_id = ClientId(id);
+        }
+        // Non-Ethernet clients have no MAC for the boot script URL unless
+        // their host entry gives one.
+        let mac = host.as_ref().and_then(|host| host.mac).
+            or_else(|| client_id.mac()).
+            unwrap_or_else(|| request_packet.client_mac());
+        // A relayed request comes from the subnet of the relay agent.
+        // Anything else is on the network the server is attached to.
+        let gateway_ip = Ipv4Addr::from(request_packet._gateway_ip);
+        let subnet_addr = if gateway_ip.is_unspecified() {
+            self.config.server_ip
+        } else {
+            gateway_ip
+        };
+        let facts = classify::ClientFacts::from_request(request_packet, options, &self.fingerprints);
+        let state = self.states.get(&mac).effective(self.config.configured_state(host.as_ref()));
+        MachineConfig{
+            client_id,
+            mac_address: mac,
+            config: &self.config,
+            subnet: self.config.subnet(subnet_addr),
+            classes: classify::classify(&self.config, host.as_ref(), &facts),
+            host,
+            facts,
+            state,
+            url_token: if self.config.tokens.enabled {
+                Some(self.tokens.sign(&mac, None, token::now() + self.config.tokens.lifetime_secs))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn new(config: ServerConfig, states: Arc<StateStore>, tokens: Arc<TokenSigner>,
+               clients: Arc<ClientInventory>, logging: bool, capture: bool,
+               capture_dir: &str) -> Result <DHCPServer, Error>  {
         let local_ip4 = IpAddr::from_str("0.0.0.0").unwrap();
-        return Ok(DHCPServer{
-            capture: capture,
+        Ok(DHCPServer{
+            provider: provider::from_config(&config, states.clone())?,
+            fingerprints: FingerprintDb::load(config.fingerprint_file.as_deref())?,
+            config,
+            states,
+            tokens,
+            clients,
+            leases: LeaseManager::new(),
+            counters: PacketCounters::default(),
+            capture,
             capture_dir: String::from_str(capture_dir).unwrap(),
             local_ipv4: local_ip4,
-            logging: logging,
+            logging,
             server_port: 67,
-        });
+        })
     }
 
     fn handle_packet(&self, socket: &UdpSocket) ->
         std::io::Result<()>
     {
         let mut packet = DHCPPacket::new();
+        let len;
         unsafe {
             let mut buf = transmute::<
                     DHCPPacket,
                 [u8; size_of::<DHCPPacket>()]>(packet);
-            let (_amt, _src) = socket.recv_from(&mut buf)?;
+            let (amt, _src) = socket.recv_from(&mut buf)?;
+            len = amt;
             packet = transmute::<[u8; size_of::<DHCPPacket>()],
                                  DHCPPacket>(buf);
         }
+        PacketCounters::count(&self.counters.received);
+        if let Err(e) = validate::validate(&packet, len) {
+            self.counters.dropped(&e);
+            if self.logging {
+                println!("dropping packet: {}", e);
+                self.counters.log();
+            }
+            return Ok(())
+        }
         if self.logging {
             println!("packet received");
             packet.log();
@@ -128,11 +550,28 @@ impl DHCPServer{
                 "{}/packet.{:?}.in.bin", self.capture_dir, date_time);
             DHCPPacket::write_to_file(&capture_file, packet);
         }
+        if self.is_ignored(&packet) {
+            PacketCounters::count(&self.counters.ignored);
+            return Ok(())
+        }
         match self.generate_response(&packet){
             Ok(response_packet)  => {
                 if self.logging {
                     println!("sending packet");
                     response_packet.log();
+                    let options = packet.parse_vendor_data().unwrap_or_default();
+                    let client_id = ClientId::from_packet(&packet, &options);
+                    if let Some(lease) = self.leases.lease(&client_id) {
+                        println!("lease: {:?}", lease);
+                    }
+                    if let Some(record) = self.clients.get(&client_id.to_string()) {
+                        println!("client sent {} requests since {}", record.total_requests(),
+                                 record.first_seen);
+                    }
+                    let reports = client_id.mac().map(|mac| self.states.get(&mac).reports);
+                    if let Some(report) = reports.as_ref().and_then(|reports| reports.last()) {
+                        println!("installer last reported {} at {}", report.stage, report.time);
+                    }
                 }
                 if self.capture{
                     let date_time = SystemTime::now().duration_since(
@@ -142,23 +581,32 @@ impl DHCPServer{
                     DHCPPacket::write_to_file(&capture_file, packet);
                 }
 
-                let dest = SocketAddr::from(
-                    (response_packet.your_ip, self.server_port));
+                // A NAK has no yiaddr to go to
+                let dest_ip = if response_packet.your_ip == [0; 4] {
+                    Ipv4Addr::BROADCAST.octets()
+                } else {
+                    response_packet.your_ip
+                };
+                let dest = SocketAddr::from((dest_ip, self.server_port));
                 unsafe {
                     let buf = transmute::<
                             DHCPPacket,[
                                 u8; size_of::<DHCPPacket>()]>(
                         response_packet);
-                    socket.send_to(&buf, &dest)?;
+                    socket.send_to(&buf, dest)?;
                 };
+                PacketCounters::count(&self.counters.answered);
                 Ok(())
             },
-            Err(s) => Err(Error::new(ErrorKind::Other, s))
+            Err(s) => {
+                PacketCounters::count(&self.counters.failed);
+                Err(Error::other(s))
+            }
         }
     }
 
     pub fn run(&self) -> std::io::Result<()> {
-        let socket = UdpSocket::bind(&SocketAddr::new(self.local_ipv4, self.server_port))?;
+        let socket = UdpSocket::bind(SocketAddr::new(self.local_ipv4, self.server_port))?;
         socket.set_broadcast(true).expect("set_broadcast call failed");
 
         if self.capture{
@@ -166,52 +614,199 @@ impl DHCPServer{
         }
         println!("size of Boot Packet layout  = {0}",
                  size_of::<DHCPPacket>());
+        // One bad packet must not take the server down
         loop {
-            self.handle_packet(&socket)?
+            if let Err(e) = self.handle_packet(&socket) {
+                println!("error handling packet: {}", e);
+            }
         }
     }
-    fn set_common_fields(&self, request_packet: &DHCPPacket, response_packet:  &mut DHCPPacket){
-        let server_hostname = "ayoungP40";
-        response_packet._server_host_name[0..server_hostname.len()].
-            copy_from_slice(server_hostname.as_bytes());
+    fn set_common_fields(&self, config: &MachineConfig, request_packet: &DHCPPacket, response_packet:  &mut DHCPPacket){
+        // sname has to keep a terminating null.  A name that does not fit
+        // is still sent in option 66.
+        if let Some(server_hostname) = config.tftp_server_name() {
+            if server_hostname.len() < response_packet._server_host_name.len() {
+                response_packet._server_host_name[0..server_hostname.len()].
+                    copy_from_slice(server_hostname.as_bytes());
+            }
+        }
         response_packet._vendor_magic = VENDOR_MAGIC;
         response_packet.opcode = DHCPOptCodes::RESPONSE as u8;
         response_packet._hwtype = request_packet._hwtype;
         response_packet._hw_addr_len =  request_packet._hw_addr_len;
         response_packet._client_mac =  request_packet._client_mac;
+        response_packet._client_mac_remainder =  request_packet._client_mac_remainder;
         response_packet._txn_id =  request_packet._txn_id;
-        let config = self.machine_config(&request_packet.client_mac());
-        response_packet._server_ip =  config.server_ip().octets();
-        response_packet.your_ip =  config.your_ip().octets();
+        response_packet._flags =  request_packet._flags;
+        response_packet._gateway_ip =  request_packet._gateway_ip;
+        response_packet._server_ip =  config.next_server().octets();
 
     }
 
-    fn handle_dhcprequest(&self, request_packet: &DHCPPacket) ->  Result<DHCPPacket, &'static str>{
-        let mut response_packet = DHCPPacket::new();
-        self.set_common_fields(request_packet, &mut response_packet);
+    /// Fills in the boot file for the client.  PXE clients get a path
+    /// relative to the TFTP server in the file field.  UEFI HTTP Boot
+    /// clients get a full URL, and will ignore the offer unless the
+    /// vendor class "HTTPClient" is echoed back to them.  Clients that
+    /// are already running iPXE get the URL of their boot script, if
+    /// they can fetch it.
+    fn set_boot_file(&self, config: &MachineConfig,
+                     options: &HashMap::<DHCPOptionCode, VendorData>,
+                     response_packet: &mut DHCPPacket,
+                     vendor_data: &mut Vec::<VendorData>) -> Result<(), &'static str>{
+        let boot_file_name = if is_ipxe_client(options) {
+            // A garbled option 175 costs the client its feature list,
+            // not its boot.
+            let ipxe_features = match options.get(&DHCPOptionCode::Etherboot){
+                Some(option) => IpxeFeatures::parse(&option.data).unwrap_or_else(|e| {
+                    println!("ignoring iPXE option 175 from {}: {}", config.mac_address, e);
+                    IpxeFeatures::default()
+                }),
+                None => IpxeFeatures::default()
+            };
+            config.ipxe_boot_file_name(&ipxe_features)
+        } else if is_http_boot_client(options) {
+            vendor_data.push(VendorData::new(DHCPOptionCode::VendorClassIdentifier,
+                HTTP_CLIENT.as_bytes())?);
+            config.http_boot_url()
+        } else {
+            config.boot_file_name()
+        };
+
+        // The file field has to hold a terminating null.  A URL that does
+        // not fit goes in the Bootfile Name option instead.
+        let fits = boot_file_name.len() < response_packet._boot_file_name.len();
+        if fits {
+            response_packet._boot_file_name[0..boot_file_name.len()].
+                copy_from_slice(boot_file_name.as_bytes());
+        }
+        if !fits || is_requested(options, DHCPOptionCode::BootfileName) {
+            if boot_file_name.len() > u8::MAX as usize {
+                return Err("boot file name too long");
+            }
+            vendor_data.push(VendorData::new(DHCPOptionCode::BootfileName,
+                boot_file_name.as_bytes())?);
+        }
+        if let Some(server_name) = config.tftp_server_name() {
+            vendor_data.push(VendorData::new(DHCPOptionCode::TFTPServerName,
+                server_name.as_bytes())?);
+        }
+        Ok(())
+    }
 
-        let config = self.machine_config(&request_packet.client_mac());
+    /// PXE clients ignore replies that do not follow the PXE
+    /// specification, so those get a few more options.
+    fn add_pxe_compliance(&self, config: &MachineConfig,
+                          options: &HashMap::<DHCPOptionCode, VendorData>,
+                          response_packet: &mut DHCPPacket,
+                          vendor_data: &mut Vec::<VendorData>) -> Result<(), &'static str>{
+        if pxe::is_pxe_client(options) {
+            pxe::add_pxe_options(options, vendor_data)?;
+            pxe::set_pxe_fields(config.next_server(), response_packet);
+        }
+        Ok(())
+    }
 
+    fn handle_dhcprequest(&self, config: &MachineConfig, request_packet: &DHCPPacket,
+                          options: &HashMap::<DHCPOptionCode, VendorData>) ->  Result<DHCPPacket, &'static str>{
+        self.acknowledge(config, request_packet, options, false)
+    }
 
-        let mut vendor_data:Vec::<VendorData> = vec!();
+    /// A one-shot state is used up by the boot that fetches its iPXE
+    /// script, or, when its profile gives a boot file for clients that
+    /// are not running iPXE, by the ACK that sends it.
+    fn use_one_shot_state(&self, config: &MachineConfig,
+                          options: &HashMap::<DHCPOptionCode, VendorData>) {
+        let sends_boot_file = config.state_profile().is_some_and(|profile| profile.boot_file.is_some());
+        if !sends_boot_file || is_ipxe_client(options) || is_http_boot_client(options) {
+            return;
+        }
+        if self.states.get(&config.mac_address).once.is_some() {
+            if let Err(e) = self.states.set_once(&config.mac_address, None) {
+                println!("cannot save boot state of {}: {}", config.mac_address, e);
+            }
+        }
+    }
+
+    /// Commits the lease and builds the ACK, for a REQUEST or for a
+    /// DISCOVER with rapid commit, which the ACK has to confirm.
+    fn acknowledge(&self, config: &MachineConfig, request_packet: &DHCPPacket,
+                   options: &HashMap::<DHCPOptionCode, VendorData>,
+                   rapid_commit: bool) ->  Result<DHCPPacket, &'static str>{
+        // A client that picked another server's offer names that server
+        // in option 54, and is left alone.
+        if server_identifier(options).is_some_and(|server| server != config.dhcp_server()) {
+            return Err("request is for another server");
+        }
+        let mut response_packet = DHCPPacket::new();
+        self.set_common_fields(config, request_packet, &mut response_packet);
 
-        vendor_data.push(VendorData::new(DHCPOptionCode::DHCPMessageType,
-             &vec![DHCPMessageType::DHCPACK as u8])?);
+        // A client in INIT-REBOOT or RENEWING puts its address in ciaddr
+        // rather than in option 50.  With rapid commit option 50 is only
+        // a hint, so the address is picked as for an OFFER.
+        let client_ip = Ipv4Addr::from(request_packet._client_ip);
+        let requested = if rapid_commit {
+            Some(self.leases.offer(&config.client_id, config.reserved_ip(),
+                                   requested_ip(options), config.pool().as_ref())
+                 .ok_or("no addresses available")?)
+        } else {
+            requested_ip(options).or(
+                if client_ip.is_unspecified() { None } else { Some(client_ip) })
+        };
+        let lease_time = config.lease_time(requested_lease_time(options));
+        let your_ip = match self.leases.commit(
+            &config.client_id, config.reserved_ip(), requested,
+            config.pool().as_ref(),
+            Some(Duration::from_secs(lease_time as u64))) {
+            Some(your_ip) => your_ip,
+            None if requested.is_some() => return self.negative_acknowledge(config, request_packet),
+            None => return Err("no addresses available")
+        };
+        response_packet.your_ip = your_ip.octets();
+
+        let mut vendor_data:Vec::<VendorData> = vec![
+            VendorData::new(DHCPOptionCode::DHCPMessageType,
+                &[DHCPMessageType::DHCPACK as u8])?];
+        if rapid_commit {
+            vendor_data.push(VendorData::new(DHCPOptionCode::RapidCommit, &[])?);
+        }
+
+        self.set_boot_file(config, options, &mut response_packet, &mut vendor_data)?;
+        self.add_pxe_compliance(config, options, &mut response_packet, &mut vendor_data)?;
+        self.use_one_shot_state(config, options);
 
         vendor_data.push(VendorData::new(DHCPOptionCode::SubnetMask,
-            &config.subnet_mask().octets().to_vec())?);
+            &config.subnet_mask().octets())?);
 
         vendor_data.push(VendorData::new(DHCPOptionCode::Router,
-            &config.router().octets().to_vec())?);
+            &config.router().octets())?);
+
+        vendor_data.push(VendorData::new(DHCPOptionCode::BroadcastAddress,
+            &config.broadcast_address().octets())?);
+
+        let classless_routes = config.classless_routes();
+        if !classless_routes.is_empty() {
+            let encoded = routes::encode_routes(&classless_routes)?;
+            if encoded.len() > u8::MAX as usize {
+                return Err("classless static routes too long");
+            }
+            if is_requested(options, DHCPOptionCode::ClasslessStaticRoute) {
+                vendor_data.push(VendorData::new(
+                    DHCPOptionCode::ClasslessStaticRoute, &encoded)?);
+            }
+            if is_requested(options, DHCPOptionCode::MsClasslessStaticRoute) {
+                vendor_data.push(VendorData::new(
+                    DHCPOptionCode::MsClasslessStaticRoute, &encoded)?);
+            }
+        }
 
-        vendor_data.push(VendorData::new(DHCPOptionCode::IPAddressLeaseTime,
-            &u32::to_be_bytes(config.lease_time()).to_vec())?);
+        vendor_data.extend(lease_time_options(lease_time)?);
 
         vendor_data.push(VendorData::new(DHCPOptionCode::DHCPServer,
-            &config.dhcp_server().octets().to_vec())?);
+            &config.dhcp_server().octets())?);
 
         vendor_data.push(VendorData::new(DHCPOptionCode::DNSServers,
             &config.dns_servers())?);
+        add_custom_options(config, &mut vendor_data)?;
         vendor_data.push(VendorData::END);
 
 
@@ -223,24 +818,99 @@ impl DHCPServer{
         Ok(response_packet)
     }
 
-    fn handle_dhcpdiscover(&self, request_packet: &DHCPPacket) ->  Result<DHCPPacket, &'static str>{
+    /// Refuses a REQUEST for an address the client cannot have: one
+    /// outside its pool, held by another client, or other than its
+    /// reservation.  The client starts over with a DISCOVER.
+    fn negative_acknowledge(&self, config: &MachineConfig, request_packet: &DHCPPacket)
+                            -> Result<DHCPPacket, &'static str> {
         let mut response_packet = DHCPPacket::new();
+        self.set_common_fields(config, request_packet, &mut response_packet);
+        response_packet._server_ip = [0; 4];
+        response_packet._server_host_name = [0; 64];
+        // A relay has to broadcast the NAK, as the client may have no
+        // address to send it to.
+        if request_packet._gateway_ip != [0; 4] {
+            response_packet._flags[0] |= 0x80;
+        }
+        let vendor_data = [
+            VendorData::new(DHCPOptionCode::DHCPMessageType,
+                &[DHCPMessageType::DHCPNAK as u8])?,
+            VendorData::new(DHCPOptionCode::DHCPServer,
+                &config.dhcp_server().octets())?,
+            VendorData::END];
+        let mut offset = 0;
+        for opt in vendor_data{
+            offset = opt.write(&mut response_packet._vendor_info, offset)
+        }
+        Ok(response_packet)
+    }
+
+    fn handle_dhcpdiscover(&self, config: &MachineConfig, request_packet: &DHCPPacket,
+                           options: &HashMap::<DHCPOptionCode, VendorData>) ->  Result<DHCPPacket, &'static str>{
+        let mut response_packet = DHCPPacket::new();
+        if config.rapid_commit() && options.contains_key(&DHCPOptionCode::RapidCommit) {
+            return self.acknowledge(config, request_packet, options, true);
+        }
+        self.set_common_fields(config, request_packet, &mut response_packet);
+
+        let your_ip = self.leases.offer(
+            &config.client_id, config.reserved_ip(), requested_ip(options),
+            config.pool().as_ref()).ok_or("no addresses available")?;
+        response_packet.your_ip = your_ip.octets();
 
-        self.set_common_fields(request_packet, &mut response_packet);
+        let mut vendor_data:Vec::<VendorData> = vec![
+            VendorData::new(DHCPOptionCode::DHCPMessageType,
+                &[DHCPMessageType::DHCPOFFER as u8])?];
 
-        let config = self.machine_config(&request_packet.client_mac());
+        self.set_boot_file(config, options, &mut response_packet, &mut vendor_data)?;
+        self.add_pxe_compliance(config, options, &mut response_packet, &mut vendor_data)?;
+        vendor_data.extend(lease_time_options(
+            config.lease_time(requested_lease_time(options)))?);
+        vendor_data.push(VendorData::new(DHCPOptionCode::DHCPServer,
+            &config.dhcp_server().octets())?);
+        let domain_search = config.domain_search();
+        if !domain_search.is_empty() {
+            let encoded = dns::encode_domain_list(&domain_search)?;
+            if encoded.len() > u8::MAX as usize {
+                return Err("domain search list too long");
+            }
+            vendor_data.push(VendorData::new(DHCPOptionCode::DomainSearch,
+                &encoded)?);
+        }
+        add_custom_options(config, &mut vendor_data)?;
+        vendor_data.push(VendorData::END);
 
-        // if the string is too long for the field, this will panic.
-        let _boot_file_name = config.boot_file_name();
-        for i in 0.._boot_file_name.len() {
-            response_packet._boot_file_name[i] =  _boot_file_name.as_bytes()[i]
+        let mut offset = 0;
+        for opt in vendor_data{
+            offset = opt.write(&mut response_packet._vendor_info, offset)
         }
-        let mut vendor_data:Vec::<VendorData> = vec!();
 
-        vendor_data.push(VendorData::new(DHCPOptionCode::DHCPMessageType,
-             &vec![DHCPMessageType::DHCPOFFER as u8])?);
-        vendor_data.push(VendorData::new(DHCPOptionCode::DomainSearch,
-            &config.domain_search().as_bytes().to_vec())?);
+        Ok(response_packet)
+    }
+
+    /// A plain BOOTP client (RFC 951) sends no DHCP message type and
+    /// has no notion of a lease.  It gets its reserved address, or a
+    /// permanent one from the pool if dynamic BOOTP is allowed.
+    fn handle_bootp(&self, config: &MachineConfig, request_packet: &DHCPPacket,
+                    options: &HashMap::<DHCPOptionCode, VendorData>) ->  Result<DHCPPacket, &'static str>{
+        let mut response_packet = DHCPPacket::new();
+        self.set_common_fields(config, request_packet, &mut response_packet);
+
+        let pool = if self.config.bootp_dynamic { config.pool() } else { None };
+        let your_ip = self.leases.commit(
+            &config.client_id, config.reserved_ip(), None, pool.as_ref(), None).
+            ok_or("no address for BOOTP client")?;
+        response_packet.your_ip = your_ip.octets();
+
+        let mut vendor_data:Vec::<VendorData> = vec!();
+        self.set_boot_file(config, options, &mut response_packet, &mut vendor_data)?;
+        vendor_data.push(VendorData::new(DHCPOptionCode::SubnetMask,
+            &config.subnet_mask().octets())?);
+        vendor_data.push(VendorData::new(DHCPOptionCode::Router,
+            &config.router().octets())?);
+        vendor_data.push(VendorData::new(DHCPOptionCode::DNSServers,
+            &config.dns_servers())?);
+        add_custom_options(config, &mut vendor_data)?;
         vendor_data.push(VendorData::END);
 
         let mut offset = 0;
@@ -251,20 +921,69 @@ impl DHCPServer{
         Ok(response_packet)
     }
 
+    /// Whether policy says to leave a request unanswered.  Such requests
+    /// are dropped without a word, unlike those that fail.
+    pub fn is_ignored(&self, request_packet: &DHCPPacket) -> bool {
+        let options = request_packet.parse_vendor_data().unwrap_or_default();
+        policy::is_ignored(&self.config, request_packet, &options)
+    }
+
+    /// Records the request in the client inventory, and logs what kind of
+    /// client sent it when that is news
+    fn observe(&self, request_packet: &DHCPPacket, options: &HashMap::<DHCPOptionCode, VendorData>,
+               client_id: &ClientId, config: &MachineConfig) {
+        let details = classify::client_details(request_packet, options, &config.facts);
+        let message = match options.get(&DHCPOptionCode::DHCPMessageType).
+            and_then(|option| option.data.first()) {
+            Some(code) => match num::FromPrimitive::from_u8(*code) {
+                Some(DHCPMessageType::DHCPDISCOVER) => "discover".to_string(),
+                Some(DHCPMessageType::DHCPREQUEST) => "request".to_string(),
+                Some(DHCPMessageType::DHCPDECLINE) => "decline".to_string(),
+                Some(DHCPMessageType::DHCPRELEASE) => "release".to_string(),
+                Some(DHCPMessageType::DHCPINFORM) => "inform".to_string(),
+                _ => format!("type {}", code)
+            },
+            None => "bootp".to_string()
+        };
+        let client = client_id.to_string();
+        let previous = self.clients.get(&client).and_then(|record| record.details.client_type);
+        if self.logging || details.client_type != previous {
+            match &details.client_type {
+                Some(client_type) => println!("{} looks like {}", client, client_type),
+                None => println!("{} has an unknown fingerprint {}", client,
+                                 details.fingerprint.as_deref().unwrap_or("-"))
+            }
+        }
+        self.clients.observe(&client, details, &message, token::now());
+    }
+
     pub fn generate_response(&self, request_packet: &DHCPPacket) ->
         Result<DHCPPacket, &'static str>
     {
-        if request_packet.vendor_magic() != VENDOR_MAGIC{
-            return Err("Bad Vendor magic value");
-        }
-        let options = match request_packet.parse_vendor_data() {
-            Ok(options) => options,
-            Err(s) => {
-                println!("Bad Packet: {}", s);
-                return Err(s)
+        // An RFC 951 BOOTP client may leave the vendor area empty
+        let options = if request_packet.vendor_magic() == VENDOR_MAGIC {
+            match request_packet.parse_vendor_data() {
+                Ok(options) => options,
+                Err(s) => {
+                    println!("Bad Packet: {}", s);
+                    return Err(s)
+                }
             }
+        } else if request_packet.vendor_magic() == [0; 4] {
+            HashMap::new()
+        } else {
+            return Err("Bad Vendor magic value");
         };
         DHCPPacket::dump_options(&options);
+        let client_id = ClientId::from_packet(request_packet, &options);
+        if client_id.is_empty() {
+            return Err("client has no hardware address or client identifier");
+        }
+        let config = self.machine_config(request_packet, &options);
+        self.observe(request_packet, &options, &client_id, &config);
+        if !config.is_boot_nic(client_id.mac()) {
+            return Err("not the designated boot NIC of its machine");
+        }
         match options.get(&DHCPOptionCode::DHCPMessageType){
             Some(option) => {
                 if option.len != 1 {
@@ -275,18 +994,20 @@ impl DHCPServer{
                     None =>  return Err("unknown message type")
                 };
                 match message_type{
-                    DHCPMessageType::DHCPDISCOVER => self.handle_dhcpdiscover(request_packet),
-                    DHCPMessageType::DHCPREQUEST => self.handle_dhcprequest(request_packet),
-                    _ => return Err("cannot handle request for type")
+                    DHCPMessageType::DHCPDISCOVER => self.handle_dhcpdiscover(&config, request_packet, &options),
+                    DHCPMessageType::DHCPREQUEST => self.handle_dhcprequest(&config, request_packet, &options),
+                    _ => Err("cannot handle request for type")
                 }
             },
-            None =>  return Err("unknown message type")
+            None => self.handle_bootp(&config, request_packet, &options)
         }
     }
 }
 
 
 #[cfg(test)]
+// The tests are older than the lints
+#[allow(clippy::assertions_on_constants, clippy::let_and_return, clippy::unused_io_amount)]
 mod tests {
     use std::env;
     use std::fs::File;
@@ -294,8 +1015,28 @@ mod tests {
     use super::*;
     use std::convert::TryFrom;
 
+    fn server(config: ServerConfig) -> DHCPServer {
+        DHCPServer::new(config, Arc::default(), Arc::default(), Arc::default(), false, false, "").
+            unwrap()
+    }
+
     fn make_test_server() -> DHCPServer{
-         DHCPServer::new(false, false, "").unwrap()
+         server(ServerConfig::default())
+    }
+
+    // The handlers, with the machine config generate_response builds
+    fn discover(server: &DHCPServer, request_packet: &DHCPPacket,
+                options: &HashMap::<DHCPOptionCode, VendorData>)
+                -> Result<DHCPPacket, &'static str> {
+        let config = server.machine_config(request_packet, options);
+        server.handle_dhcpdiscover(&config, request_packet, options)
+    }
+
+    fn request(server: &DHCPServer, request_packet: &DHCPPacket,
+               options: &HashMap::<DHCPOptionCode, VendorData>)
+               -> Result<DHCPPacket, &'static str> {
+        let config = server.machine_config(request_packet, options);
+        server.handle_dhcprequest(&config, request_packet, options)
     }
 
     fn read_discovery_packet() ->  DHCPPacket{
@@ -321,10 +1062,12 @@ mod tests {
     #[test]
     fn test_handle_discover(){
         let server = make_test_server();
-        let response_packet = server.handle_dhcpdiscover(&read_discovery_packet()).unwrap();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
         assert_eq!(response_packet.opcode, DHCPMessageType::DHCPOFFER as u8);
         let vendor_data = response_packet.parse_vendor_data().unwrap();
-        assert_eq!(vendor_data.len(), 2);
+        assert_eq!(vendor_data.len(), 10);
         match vendor_data.get(&DHCPOptionCode::DHCPMessageType){
             Some(option) =>  {
                 assert_eq!(DHCPOptionCode::DHCPMessageType as u8, option.code);
@@ -337,14 +1080,747 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_domain_search_encoding(){
+        let config = ServerConfig{
+            domain_search: vec!["lab.younglogic.net".to_string(),
+                                "younglogic.net".to_string()],
+            ..ServerConfig::default()
+        };
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        match vendor_data.get(&DHCPOptionCode::DomainSearch){
+            Some(option) =>  {
+                assert_eq!(b"\x03lab\x0ayounglogic\x03net\x00\xc0\x04".to_vec(),
+                           option.data);
+                assert_eq!(vec!["lab.younglogic.net", "younglogic.net"],
+                           dns::decode_domain_list(&option.data).unwrap());
+            },
+            None => panic!("Vendor data is missing option 119")
+        }
+    }
+
+    #[test]
+    fn test_handle_discover_pxe_compliance(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!([192, 168, 144, 1], response_packet._server_ip);
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        match vendor_data.get(&DHCPOptionCode::VendorClassIdentifier){
+            Some(option) => assert_eq!(b"PXEClient".to_vec(), option.data),
+            None => panic!("Vendor data is missing option 60")
+        }
+        match vendor_data.get(&DHCPOptionCode::ClientMachineIdentifier){
+            Some(option) => assert_eq!(
+                options.get(&DHCPOptionCode::ClientMachineIdentifier).unwrap().data,
+                option.data),
+            None => panic!("Vendor data is missing option 97")
+        }
+        match vendor_data.get(&DHCPOptionCode::DHCPServer){
+            Some(option) => assert_eq!(vec![192, 168, 144, 1], option.data),
+            None => panic!("Vendor data is missing option 54")
+        }
+        assert!(vendor_data.contains_key(&DHCPOptionCode::VendorSpecificInformation));
+    }
+
+    #[test]
+    fn test_server_identifier_is_server_ip(){
+        let config = ServerConfig {
+            server_ip: Ipv4Addr::new(10, 0, 0, 2),
+            ..ServerConfig::default()
+        };
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let offer = discover(&server, &request_packet, &options).unwrap();
+        let ack = request(&server, &request_packet, &options).unwrap();
+        for response_packet in [offer, ack] {
+            let vendor_data = response_packet.parse_vendor_data().unwrap();
+            assert_eq!(vec![10, 0, 0, 2],
+                       vendor_data.get(&DHCPOptionCode::DHCPServer).unwrap().data);
+        }
+    }
+
+    #[test]
+    fn test_handle_discover_ipxe(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let url = "http://192.168.144.1:8080/ipxe/52:54:00:94:9e:f2";
+        assert_eq!(url.as_bytes(), &response_packet._boot_file_name[0..url.len()]);
+        assert_eq!(0, response_packet._boot_file_name[url.len()]);
+    }
+
+    #[test]
+    fn test_script_url_carries_token(){
+        let mut config = ServerConfig::default();
+        config.tokens.enabled = true;
+        let tokens = Arc::new(TokenSigner::generate().unwrap());
+        let server = DHCPServer::new(config, Arc::default(), tokens.clone(), Arc::default(),
+                                     false, false, "").unwrap();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        let url = String::from_utf8(
+            vendor_data.get(&DHCPOptionCode::BootfileName).unwrap().data.clone()).unwrap();
+        let (url, token) = url.split_once("?token=").unwrap();
+        assert_eq!("http://192.168.144.1:8080/ipxe/52:54:00:94:9e:f2", url);
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2]);
+        assert_eq!(Ok(()), tokens.verify(token, &mac, None, token::now()));
+    }
+
+    #[test]
+    fn test_handle_discover_ipxe_https(){
+        let mut config = ServerConfig::default();
+        config.ipxe.https_base_url = Some("https://boot.example.com/".to_string());
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+
+        // The fixture's iPXE has no HTTPS, so it still gets the HTTP URL
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!(b"http://", &response_packet._boot_file_name[0..7]);
+
+        options.insert(DHCPOptionCode::Etherboot,
+                       VendorData::new(DHCPOptionCode::Etherboot,
+                                       &[0x13, 1, 1, 0x14, 1, 1]).unwrap());
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let url = "https://boot.example.com/ipxe/52:54:00:94:9e:f2";
+        assert_eq!(url.as_bytes(), &response_packet._boot_file_name[0..url.len()]);
+    }
+
+    #[test]
+    fn test_handle_discover_ipxe_without_http(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.insert(DHCPOptionCode::Etherboot,
+                       VendorData::new(DHCPOptionCode::Etherboot,
+                                       &[0x15, 1, 1]).unwrap());
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let boot_file_name = "pxelinux/pxelinux.0";
+        assert_eq!(boot_file_name.as_bytes(),
+                   &response_packet._boot_file_name[0..boot_file_name.len()]);
+    }
+
+    #[test]
+    fn test_handle_discover_malformed_ipxe_features(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        // The HTTP feature claims five bytes and carries one
+        options.insert(DHCPOptionCode::Etherboot,
+                       VendorData::new(DHCPOptionCode::Etherboot,
+                                       &[0x13, 5, 1]).unwrap());
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let boot_file_name = "pxelinux/pxelinux.0";
+        assert_eq!(boot_file_name.as_bytes(),
+                   &response_packet._boot_file_name[0..boot_file_name.len()]);
+    }
+
+    #[test]
+    fn test_boot_server_from_subnet_and_host(){
+        let config = ServerConfig::parse(
+            "[[subnets]]\n\
+             network = \"192.168.144.0\"\n\
+             netmask = \"255.255.255.0\"\n\
+             pool_start = \"192.168.144.100\"\n\
+             pool_end = \"192.168.144.200\"\n\
+             next_server = \"192.168.144.5\"\n\
+             tftp_server_name = \"artifacts\"\n\
+             boot_file = \"subnet.0\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             boot_file = \"host.0\"\n").unwrap();
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::UserClassInfo);
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!([192, 168, 144, 5], response_packet._server_ip);
+        assert_eq!(b"artifacts\0", &response_packet._server_host_name[0..10]);
+        assert_eq!(b"host.0\0", &response_packet._boot_file_name[0..7]);
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        match vendor_data.get(&DHCPOptionCode::TFTPServerName){
+            Some(option) => assert_eq!(b"artifacts".to_vec(), option.data),
+            None => panic!("Vendor data is missing option 66")
+        }
+        match vendor_data.get(&DHCPOptionCode::BootfileName){
+            Some(option) => assert_eq!(b"host.0".to_vec(), option.data),
+            None => panic!("Vendor data is missing option 67")
+        }
+    }
+
+    #[test]
+    fn test_boot_file_from_state(){
+        let config = ServerConfig::parse(
+            "[states.rescue]\n\
+             boot_file = \"rescue.0\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             state = \"install\"\n\
+             boot_file = \"host.0\"\n").unwrap();
+        let states = Arc::new(StateStore::default());
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2]);
+        states.set_once(&mac, Some(BootState::Rescue)).unwrap();
+        let server = DHCPServer::new(config, states.clone(), Arc::default(), Arc::default(),
+                                     false, false, "").unwrap();
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::UserClassInfo);
+
+        // The OFFER leaves the one-shot state for the ACK to use up
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!(b"rescue.0\0", &response_packet._boot_file_name[0..9]);
+        let response_packet = request(&server, &request_packet, &options).unwrap();
+        assert_eq!(b"rescue.0\0", &response_packet._boot_file_name[0..9]);
+        assert_eq!(None, states.get(&mac).once);
+        let response_packet = request(&server, &request_packet, &options).unwrap();
+        assert_eq!(b"host.0\0", &response_packet._boot_file_name[0..7]);
+    }
+
+    #[test]
+    fn test_unknown_machine_discovers(){
+        let config = ServerConfig::parse(
+            "[discovery]\n\
+             enabled = true\n\
+             [states.discover]\n\
+             boot_file = \"discover.0\"\n").unwrap();
+        let states = Arc::new(StateStore::default());
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2]);
+        let server = DHCPServer::new(config, states.clone(), Arc::default(), Arc::default(),
+                                     false, false, "").unwrap();
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::UserClassInfo);
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!(b"discover.0\0", &response_packet._boot_file_name[0..11]);
+
+        // Once approved, the machine is a host like any other
+        states.report_facts(&mac, Default::default(), 1).unwrap();
+        let host = HostConfig { mac: Some(mac), boot_file: Some("host.0".to_string()),
+                                ..HostConfig::default() };
+        states.approve(&mac, Some(host)).unwrap().unwrap();
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!(b"host.0\0", &response_packet._boot_file_name[0..7]);
+    }
+
+    struct CountingProvider(std::sync::atomic::AtomicUsize);
+
+    impl HostProvider for CountingProvider {
+        fn lookup(&self, _key: &HostKey) -> Result<Option<HostConfig>, String> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_one_host_lookup_per_packet(){
+        let mut server = make_test_server();
+        let provider = Arc::new(CountingProvider(Default::default()));
+        server.provider = provider.clone();
+        server.generate_response(&read_discovery_packet()).unwrap();
+        assert_eq!(1, provider.0.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_client_inventory(){
+        let clients = Arc::new(ClientInventory::default());
+        let server = DHCPServer::new(ServerConfig::default(), Arc::default(), Arc::default(),
+                                     clients.clone(), false, false, "").unwrap();
+        let mut request_packet = read_discovery_packet();
+        request_packet._gateway_ip = [10, 0, 1, 1];
+        server.generate_response(&request_packet).unwrap();
+        server.generate_response(&request_packet).unwrap();
+        let record = clients.get("01:52:54:00:94:9e:f2").unwrap();
+        assert_eq!(Some(&2), record.requests.get("discover"));
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 1, 1)), record.details.relay);
+        assert_eq!(Some(MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2])), record.details.mac);
+        assert!(record.details.vendor_class.as_ref().unwrap().starts_with("PXEClient"));
+        assert_eq!(Some("iPXE".to_string()), record.details.client_type);
+    }
+
+    #[test]
+    fn test_classify_by_client_type(){
+        let config = ServerConfig::parse(
+            "[[classes]]\n\
+             name = \"chainloaded\"\n\
+             boot_file = \"chain.efi\"\n\
+             [[rules]]\n\
+             classes = [\"chainloaded\"]\n\
+             client_type = \"iPXE\"\n").unwrap();
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let classes: Vec<String> = server.machine_config(&request_packet, &options).classes.iter().
+            map(|class| class.name.clone()).collect();
+        assert_eq!(vec!["chainloaded"], classes);
+    }
+
+    fn bootp_request() -> DHCPPacket{
+        let mut request_packet = read_discovery_packet();
+        request_packet._vendor_info = [0; 312];
+        request_packet._vendor_info[0] = DHCPOptionCode::End as u8;
+        request_packet
+    }
+
+    #[test]
+    fn test_bootp_reserved_address(){
+        let config = ServerConfig::parse(
+            "[[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             ip = \"192.168.144.50\"\n").unwrap();
+        let server = server(config);
+        let response_packet = server.generate_response(&bootp_request()).unwrap();
+        assert_eq!(DHCPOptCodes::RESPONSE as u8, response_packet.opcode);
+        assert_eq!([192, 168, 144, 50], response_packet.your_ip);
+        assert_eq!(b"pxelinux/pxelinux.0\0", &response_packet._boot_file_name[0..20]);
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        assert!(!vendor_data.contains_key(&DHCPOptionCode::DHCPMessageType));
+        assert!(!vendor_data.contains_key(&DHCPOptionCode::IPAddressLeaseTime));
+        assert!(vendor_data.contains_key(&DHCPOptionCode::SubnetMask));
+        let lease = server.leases.lease(&request_packet_mac()).unwrap();
+        assert_eq!(None, lease.expires);
+    }
+
+    #[test]
+    fn test_bootp_without_vendor_magic(){
+        let config = ServerConfig::parse(
+            "[[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             ip = \"192.168.144.50\"\n").unwrap();
+        let server = server(config);
+        let mut request_packet = bootp_request();
+        request_packet._vendor_magic = [0; 4];
+        request_packet._vendor_info = [0; 312];
+        let response_packet = server.generate_response(&request_packet).unwrap();
+        assert_eq!([192, 168, 144, 50], response_packet.your_ip);
+        request_packet._vendor_magic = [1, 2, 3, 4];
+        assert!(server.generate_response(&request_packet).is_err());
+    }
+
+    #[test]
+    fn test_bootp_dynamic(){
+        assert!(make_test_server().generate_response(&bootp_request()).is_err());
+
+        let config = ServerConfig{
+            bootp_dynamic: true,
+            ..ServerConfig::default()
+        };
+        let server = server(config);
+        let response_packet = server.generate_response(&bootp_request()).unwrap();
+        assert_eq!([192, 168, 144, 100], response_packet.your_ip);
+        assert_eq!(None, server.leases.lease(&request_packet_mac()).unwrap().expires);
+    }
+
+    fn request_packet_mac() -> ClientId{
+        let request_packet = read_discovery_packet();
+        ClientId::from_packet(&request_packet, &request_packet.parse_vendor_data().unwrap())
+    }
+
+    #[test]
+    fn test_offer_and_ack_use_the_same_address(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let offer = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!([192, 168, 144, 100], offer.your_ip);
+        let ack = request(&server, &request_packet, &options).unwrap();
+        assert_eq!(offer.your_ip, ack.your_ip);
+        assert!(server.leases.lease(&request_packet_mac()).unwrap().bound);
+    }
+
+    #[test]
+    fn test_request_for_another_server_is_dropped(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        discover(&server, &request_packet, &options).unwrap();
+        options.insert(DHCPOptionCode::DHCPServer,
+                       VendorData::new(DHCPOptionCode::DHCPServer, &[192, 168, 144, 2]).unwrap());
+        assert_eq!(Err("request is for another server"),
+                   request(&server, &request_packet, &options).map(|_| ()));
+        assert!(!server.leases.lease(&request_packet_mac()).unwrap().bound);
+
+        options.insert(DHCPOptionCode::DHCPServer,
+                       VendorData::new(DHCPOptionCode::DHCPServer, &[192, 168, 144, 1]).unwrap());
+        assert!(request(&server, &request_packet, &options).is_ok());
+    }
+
+    #[test]
+    fn test_request_for_unavailable_address_is_refused(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let ack = request(&server, &request_packet, &options).unwrap();
+        assert_eq!([192, 168, 144, 100], ack.your_ip);
+
+        let mut options = HashMap::new();
+        options.insert(DHCPOptionCode::ClientIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientIdentifier,
+                                       &[1, 2, 0, 0, 0, 0, 1]).unwrap());
+        for requested in [[192, 168, 144, 100], [10, 0, 0, 5]] {
+            options.insert(DHCPOptionCode::RequestedIPAddress,
+                           VendorData::new(DHCPOptionCode::RequestedIPAddress,
+                                           &requested).unwrap());
+            let response_packet = request(&server, &request_packet, &options).unwrap();
+            assert_eq!([0; 4], response_packet.your_ip);
+            assert_eq!([0; 4], response_packet._server_ip);
+            let vendor_data = response_packet.parse_vendor_data().unwrap();
+            assert_eq!(vec![DHCPMessageType::DHCPNAK as u8],
+                       vendor_data.get(&DHCPOptionCode::DHCPMessageType).unwrap().data);
+            assert_eq!(vec![192, 168, 144, 1],
+                       vendor_data.get(&DHCPOptionCode::DHCPServer).unwrap().data);
+        }
+        assert_eq!(None, server.leases.lease(&ClientId(vec![1, 2, 0, 0, 0, 0, 1])));
+    }
+
+    #[test]
+    fn test_response_echoes_relay_fields(){
+        let server = make_test_server();
+        let mut request_packet = read_discovery_packet();
+        request_packet._flags = [0x80, 0];
+        request_packet._gateway_ip = [192, 168, 144, 254];
+        let response_packet = server.generate_response(&request_packet).unwrap();
+        assert_eq!([0x80, 0], response_packet._flags);
+        assert_eq!([192, 168, 144, 254], response_packet._gateway_ip);
+    }
+
+    #[test]
+    fn test_truncated_option_is_an_error(){
+        let server = make_test_server();
+        let mut request_packet = read_discovery_packet();
+        request_packet._vendor_info = [0; 312];
+        request_packet._vendor_info[310] = DHCPOptionCode::DHCPMessageType as u8;
+        request_packet._vendor_info[311] = 1;
+        assert_eq!(Err("truncated option"),
+                   server.generate_response(&request_packet).map(|_| ()));
+    }
+
+    #[test]
+    fn test_infiniband_client_by_client_id(){
+        let config = ServerConfig::parse(
+            "[[hosts]]\n\
+             client_id = \"ff:00:00:00:01:00:02\"\n\
+             ip = \"192.168.144.60\"\n\
+             boot_file = \"ib.0\"\n").unwrap();
+        let server = server(config);
+        let mut request_packet = read_discovery_packet();
+        request_packet._hwtype = 32;
+        request_packet._hw_addr_len = 0;
+        request_packet._client_mac = [0; 6];
+        request_packet._vendor_info = [0; 312];
+        request_packet._vendor_info[0..4].copy_from_slice(
+            &[DHCPOptionCode::DHCPMessageType as u8, 1, DHCPMessageType::DHCPDISCOVER as u8,
+              DHCPOptionCode::End as u8]);
+        assert_eq!(Err("client has no hardware address or client identifier"),
+                   server.generate_response(&request_packet).map(|_| ()));
+
+        let mut options = HashMap::new();
+        options.insert(DHCPOptionCode::ClientIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientIdentifier,
+                                       &[0xff, 0, 0, 0, 1, 0, 2]).unwrap());
+        let response_packet = request(&server, &request_packet, &options).unwrap();
+        assert_eq!([192, 168, 144, 60], response_packet.your_ip);
+        assert_eq!(32, response_packet._hwtype);
+        assert_eq!(0, response_packet._hw_addr_len);
+        assert_eq!(b"ib.0\0", &response_packet._boot_file_name[0..5]);
+        let lease = server.leases.lease(&ClientId(vec![0xff, 0, 0, 0, 1, 0, 2])).unwrap();
+        assert_eq!(Ipv4Addr::new(192, 168, 144, 60), lease.ip);
+    }
+
+    const FIXTURE_UUID: &str = "384c23b2-c3e1-45ad-b797-d2dd220e1b9d";
+
+    #[test]
+    fn test_host_by_uuid_on_any_nic(){
+        let config = ServerConfig::parse(&format!(
+            "[[hosts]]\n\
+             uuid = \"{}\"\n\
+             ip = \"192.168.144.70\"\n\
+             boot_file = \"uuid.0\"\n", FIXTURE_UUID)).unwrap();
+        let server = server(config);
+        let mut request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::UserClassInfo);
+        options.remove(&DHCPOptionCode::ClientIdentifier);
+        for nic in &[[0x52, 0x54, 0, 0x94, 0x9e, 0xf2], [0x52, 0x54, 0, 0x11, 0x22, 0x33]] {
+            request_packet._client_mac = *nic;
+            let response_packet = request(&server, &request_packet, &options).unwrap();
+            assert_eq!([192, 168, 144, 70], response_packet.your_ip);
+            assert_eq!(b"uuid.0\0", &response_packet._boot_file_name[0..7]);
+        }
+        let config = server.machine_config(&request_packet, &options);
+        assert_eq!(17, config.client_id.0.len());
+        assert!(server.leases.lease(&config.client_id).is_some());
+    }
+
+    #[test]
+    fn test_only_the_boot_nic_is_answered(){
+        let config = ServerConfig::parse(&format!(
+            "[[hosts]]\n\
+             uuid = \"{}\"\n\
+             boot_nic = \"52:54:00:11:22:33\"\n", FIXTURE_UUID)).unwrap();
+        let server = server(config);
+        let mut request_packet = read_discovery_packet();
+        assert_eq!(Err("not the designated boot NIC of its machine"),
+                   server.generate_response(&request_packet).map(|_| ()));
+        request_packet._client_mac = [0x52, 0x54, 0, 0x11, 0x22, 0x33];
+        // The fixture's option 61 names the first NIC
+        request_packet._vendor_info = [0; 312];
+        let options = read_discovery_packet().parse_vendor_data().unwrap();
+        let mut offset = 0;
+        for (code, option) in options {
+            if code != DHCPOptionCode::ClientIdentifier {
+                offset = option.write(&mut request_packet._vendor_info, offset);
+            }
+        }
+        VendorData::END.write(&mut request_packet._vendor_info, offset);
+        assert!(server.generate_response(&request_packet).is_ok());
+    }
+
+    #[test]
+    fn test_lease_time_bounds_and_timers(){
+        let config = ServerConfig::parse(
+            "default_lease_time = 3600\n\
+             [[subnets]]\n\
+             network = \"192.168.144.0\"\n\
+             netmask = \"255.255.255.0\"\n\
+             pool_start = \"192.168.144.100\"\n\
+             pool_end = \"192.168.144.200\"\n\
+             max_lease_time = 7200\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             min_lease_time = 600\n").unwrap();
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        let config = server.machine_config(&request_packet, &options);
+        assert_eq!(3600, config.lease_time(None));
+        assert_eq!(7200, config.lease_time(Some(86400)));
+        assert_eq!(600, config.lease_time(Some(10)));
+        assert_eq!(1800, config.lease_time(Some(1800)));
+
+        options.insert(DHCPOptionCode::IPAddressLeaseTime,
+                       VendorData::new(DHCPOptionCode::IPAddressLeaseTime,
+                                       &1800u32.to_be_bytes()).unwrap());
+        let response_packet = request(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        let value = |code| vendor_data.get(&code).map(|option: &VendorData| option.data.clone());
+        assert_eq!(Some(1800u32.to_be_bytes().to_vec()), value(DHCPOptionCode::IPAddressLeaseTime));
+        assert_eq!(Some(900u32.to_be_bytes().to_vec()), value(DHCPOptionCode::RenewalTime));
+        assert_eq!(Some(1575u32.to_be_bytes().to_vec()), value(DHCPOptionCode::RebindingTime));
+    }
+
+    #[test]
+    fn test_infinite_lease_never_renews(){
+        let options = lease_time_options(u32::MAX).unwrap();
+        assert!(options.iter().all(|option| option.data == vec![0xff; 4]));
+    }
+
+    #[test]
+    fn test_rapid_commit(){
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.insert(DHCPOptionCode::RapidCommit,
+                       VendorData::new(DHCPOptionCode::RapidCommit, &[]).unwrap());
+
+        // Off by default: an ordinary OFFER and a held address
+        let off = make_test_server();
+        let response_packet = discover(&off, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        assert_eq!(vec![DHCPMessageType::DHCPOFFER as u8],
+                   vendor_data.get(&DHCPOptionCode::DHCPMessageType).unwrap().data);
+        assert!(!vendor_data.contains_key(&DHCPOptionCode::RapidCommit));
+        assert!(!off.leases.lease(&request_packet_mac()).unwrap().bound);
+
+        let config = ServerConfig{
+            rapid_commit: true,
+            ..ServerConfig::default()
+        };
+        let other = server(config.clone());
+        let server = server(config);
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        assert_eq!(vec![DHCPMessageType::DHCPACK as u8],
+                   vendor_data.get(&DHCPOptionCode::DHCPMessageType).unwrap().data);
+        assert_eq!(Vec::<u8>::new(), vendor_data.get(&DHCPOptionCode::RapidCommit).unwrap().data);
+        assert!(server.leases.lease(&request_packet_mac()).unwrap().bound);
+
+        // A client that does not ask for it still gets an OFFER
+        options.remove(&DHCPOptionCode::RapidCommit);
+        discover(&other, &request_packet, &options).unwrap();
+        assert!(!other.leases.lease(&request_packet_mac()).unwrap().bound);
+    }
+
+    #[test]
+    fn test_cidr_subnet_and_classless_routes(){
+        let config = ServerConfig::parse(
+            "[[subnets]]\n\
+             network = \"192.168.144.0/23\"\n\
+             pool_start = \"192.168.144.100\"\n\
+             pool_end = \"192.168.144.200\"\n\
+             router = \"192.168.144.254\"\n\
+             routes = [{ destination = \"10.9.0.0/16\", \
+                         gateway = \"192.168.144.253\" }]\n").unwrap();
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.insert(DHCPOptionCode::ParameterRequestList,
+                       VendorData::new(DHCPOptionCode::ParameterRequestList,
+                                       &[1, 3, 28, 121]).unwrap());
+        let response_packet = request(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        let value = |code| vendor_data.get(&code).map(|option: &VendorData| option.data.clone());
+        assert_eq!(Some(vec![255, 255, 254, 0]), value(DHCPOptionCode::SubnetMask));
+        assert_eq!(Some(vec![192, 168, 145, 255]), value(DHCPOptionCode::BroadcastAddress));
+        assert_eq!(Some(vec![192, 168, 144, 254]), value(DHCPOptionCode::Router));
+        assert_eq!(Some(vec![16, 10, 9, 192, 168, 144, 253, 0, 192, 168, 144, 254]),
+                   value(DHCPOptionCode::ClasslessStaticRoute));
+        assert_eq!(None, value(DHCPOptionCode::MsClasslessStaticRoute));
+    }
+
+    #[test]
+    fn test_custom_option_inheritance(){
+        let config = ServerConfig::parse(
+            "options = [{ code = 42, ip = [\"10.0.0.1\"] },\n\
+                        { code = 101, text = \"America/New_York\" }]\n\
+             [[subnets]]\n\
+             network = \"192.168.144.0/24\"\n\
+             pool_start = \"192.168.144.100\"\n\
+             pool_end = \"192.168.144.200\"\n\
+             options = [{ code = 42, ip = [\"10.0.0.2\"] }, { code = 26, uint16 = 1500 }]\n\
+             [[classes]]\n\
+             name = \"jumbo\"\n\
+             options = [{ code = 26, uint16 = 9000 }]\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             classes = [\"jumbo\"]\n\
+             options = [{ code = 3, hex = \"c0a89002\" }]\n").unwrap();
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let config = server.machine_config(&request_packet, &options);
+        let custom: Vec<(u8, Vec<u8>)> = config.custom_options().unwrap().into_iter().
+            map(|option| (option.code, option.data)).collect();
+        assert_eq!(vec![(42, vec![10, 0, 0, 2]),
+                        (101, b"America/New_York".to_vec()),
+                        (26, vec![0x23, 0x28]),
+                        (3, vec![192, 168, 144, 2])], custom);
+
+        // A configured option replaces the one the handler built
+        let response_packet = request(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        assert_eq!(vec![192, 168, 144, 2], vendor_data.get(&DHCPOptionCode::Router).unwrap().data);
+        let info = &response_packet._vendor_info;
+        assert!(info.windows(6).any(|window| window == [42, 4, 10, 0, 0, 2]));
+    }
+
+    #[test]
+    fn test_classification_rules(){
+        let config = ServerConfig::parse(
+            "[[classes]]\n\
+             name = \"kvm\"\n\
+             boot_file = \"kvm.0\"\n\
+             pool_start = \"192.168.144.150\"\n\
+             pool_end = \"192.168.144.160\"\n\
+             options = [{ code = 26, uint16 = 9000 }]\n\
+             [[classes]]\n\
+             name = \"dell\"\n\
+             boot_file = \"dell.0\"\n\
+             [[rules]]\n\
+             oui = \"52:54:00\"\n\
+             classes = [\"kvm\"]\n\
+             [[rules]]\n\
+             oui = \"00:1a:4b\"\n\
+             classes = [\"dell\"]\n").unwrap();
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::UserClassInfo);
+        let config = server.machine_config(&request_packet, &options);
+        let names: Vec<&str> = config.classes().iter().map(|class| class.name.as_str()).collect();
+        assert_eq!(vec!["kvm"], names);
+        assert_eq!(1, config.custom_options().unwrap().len());
+
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!([192, 168, 144, 150], response_packet.your_ip);
+        assert_eq!(b"kvm.0\0", &response_packet._boot_file_name[0..6]);
+    }
+
+    #[test]
+    fn test_pxe_only_ignores_other_clients(){
+        let server = server(ServerConfig{ pxe_only: true, ..ServerConfig::default() });
+        let mut request_packet = read_discovery_packet();
+        assert!(!server.is_ignored(&request_packet));
+        request_packet._vendor_info = [0; 312];
+        request_packet._vendor_info[0..4].copy_from_slice(
+            &[DHCPOptionCode::DHCPMessageType as u8, 1, DHCPMessageType::DHCPDISCOVER as u8,
+              DHCPOptionCode::End as u8]);
+        assert!(server.is_ignored(&request_packet));
+        assert!(!make_test_server().is_ignored(&request_packet));
+    }
+
+    fn http_boot_options(request_packet: &DHCPPacket) ->
+        HashMap::<DHCPOptionCode, VendorData>
+    {
+        let mut options = request_packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::UserClassInfo);
+        options.insert(DHCPOptionCode::VendorClassIdentifier,
+                       VendorData::new(DHCPOptionCode::VendorClassIdentifier,
+                           b"HTTPClient:Arch:00016:UNDI:003001").unwrap());
+        options
+    }
+
+    #[test]
+    fn test_handle_discover_http_boot(){
+        let server = make_test_server();
+        let request_packet = read_discovery_packet();
+        let options = http_boot_options(&request_packet);
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        match vendor_data.get(&DHCPOptionCode::VendorClassIdentifier){
+            Some(option) => assert_eq!(b"HTTPClient".to_vec(), option.data),
+            None => panic!("Vendor data is missing option 60")
+        }
+        let url = "http://192.168.144.1/efi/bootx64.efi";
+        assert_eq!(url.as_bytes(), &response_packet._boot_file_name[0..url.len()]);
+        assert_eq!(0, response_packet._boot_file_name[url.len()]);
+    }
+
+    #[test]
+    fn test_http_boot_url_too_long_for_file_field(){
+        let mut config = ServerConfig::default();
+        config.http_boot.boot_file = "x".repeat(200);
+        let server = server(config);
+        let request_packet = read_discovery_packet();
+        let options = http_boot_options(&request_packet);
+        let response_packet = discover(&server, &request_packet, &options).unwrap();
+        assert_eq!([0; 128], response_packet._boot_file_name);
+        let vendor_data = response_packet.parse_vendor_data().unwrap();
+        match vendor_data.get(&DHCPOptionCode::BootfileName){
+            Some(option) => assert_eq!(server.config.http_boot.url().as_bytes(),
+                                       &option.data[..]),
+            None => panic!("Vendor data is missing option 67")
+        }
+    }
+
     #[test]
     fn test_handle_dhcprequest(){
         let server = make_test_server();
 
-        let response_packet = server.handle_dhcprequest(&read_discovery_packet()).unwrap();
+        let request_packet = read_discovery_packet();
+        let options = request_packet.parse_vendor_data().unwrap();
+        let response_packet = request(&server, &request_packet, &options).unwrap();
         assert_eq!(response_packet.opcode, DHCPOptCodes::RESPONSE as u8);
         let vendor_data = response_packet.parse_vendor_data().unwrap();
-        assert_eq!(vendor_data.len(), 6);
+        assert_eq!(vendor_data.len(), 13);
         match vendor_data.get(&DHCPOptionCode::DHCPMessageType){
             Some(option) =>  {
                 assert_eq!(DHCPOptionCode::DHCPMessageType as u8, option.code);
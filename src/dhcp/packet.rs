@@ -16,6 +16,50 @@ pub struct VendorData{
 
 pub const VENDOR_MAGIC:[u8; 4] = [99,130,83,99];
 
+const HWTYPE_ETHERNET: u8 = 1;
+
+/// Who a client is, as RFC 2131 section 4.2 defines it: the contents of
+/// the client identifier option if there is one, and otherwise the
+/// hardware type followed by the hardware address.  The two agree for
+/// an Ethernet client that sends option 61 with type 1.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ClientId(pub Vec<u8>);
+
+impl ClientId {
+    pub fn from_packet(packet: &DHCPPacket,
+                       options: &HashMap::<DHCPOptionCode, VendorData>) -> ClientId{
+        match options.get(&DHCPOptionCode::ClientIdentifier){
+            Some(option) if !option.data.is_empty() => ClientId(option.data.clone()),
+            _ => {
+                let mut id = vec![packet._hwtype];
+                id.extend_from_slice(&packet.hardware_address());
+                ClientId(id)
+            }
+        }
+    }
+
+    /// The Ethernet address in the identifier, if there is one.
+    pub fn mac(&self) -> Option<MacAddress>{
+        match self.0.as_slice() {
+            [HWTYPE_ETHERNET, a, b, c, d, e, f] => Some(MacAddress::new([*a, *b, *c, *d, *e, *f])),
+            _ => None
+        }
+    }
+
+    /// An identity made only of the hardware type says nothing about
+    /// which client sent it.
+    pub fn is_empty(&self) -> bool{
+        self.0.len() < 2
+    }
+}
+
+impl std::fmt::Display for ClientId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let octets: Vec<String> = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(f, "{}", octets.join(":"))
+    }
+}
+
 impl VendorData{
 
     pub const END: VendorData = VendorData{
@@ -24,14 +68,26 @@ impl VendorData{
         data: vec![]
     };
 
-    pub fn new(code: DHCPOptionCode, data: &Vec<u8>) ->Result<VendorData, &'static str>{
+    /// An option by number, for codes rustboot does not model
+    pub fn raw(code: u8, data: &[u8]) -> Result<VendorData, &'static str>{
+        if data.len() > u8::MAX as usize {
+            return Err("vendor data too long");
+        }
+        Ok(VendorData {
+            code,
+            len: data.len() as u8,
+            data: data.to_vec()
+        })
+    }
+
+    pub fn new(code: DHCPOptionCode, data: &[u8]) ->Result<VendorData, &'static str>{
         let len = data.len();
 
         //Kindof bogus, as no single field will be this long.
         if len > 312 {
-            return Err("vendor data too long")
+            Err("vendor data too long")
         }else{
-            return Ok(VendorData {
+            Ok(VendorData {
                 code: code as u8,
                 len: data.len() as u8,
                 data: data.to_vec()
@@ -80,6 +136,8 @@ impl VendorData{
 #[derive(Eq, Hash, PartialEq,
          ::num_derive::FromPrimitive,::num_derive::ToPrimitive)]
 #[repr(u8)]
+#[num_traits = "num_traits"]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DHCPOptionCode{
     Pad = 0,
 
@@ -93,6 +151,12 @@ pub enum DHCPOptionCode{
     DNSServers=6,
     // https://tools.ietf.org/html/rfc2132#section-3.14
     HOSTNAME=12,
+    // https://tools.ietf.org/html/rfc2132#section-5.3
+    BroadcastAddress = 28,
+    // https://tools.ietf.org/html/rfc2132#section-8.4
+    VendorSpecificInformation = 43,
+    // https://tools.ietf.org/html/rfc2132#section-9.1
+    RequestedIPAddress = 50,
     // https://tools.ietf.org/html/rfc2132#section-9.2
     IPAddressLeaseTime = 51,
     // https://tools.ietf.org/html/rfc2132#section-9.7
@@ -103,12 +167,24 @@ pub enum DHCPOptionCode{
     ParameterRequestList = 55,
     // https://tools.ietf.org/html/rfc1533#section-9.8
     MaximumDHCPMessageSize = 57,
+    // https://tools.ietf.org/html/rfc2132#section-9.11
+    RenewalTime = 58,
+    // https://tools.ietf.org/html/rfc2132#section-9.12
+    RebindingTime = 59,
     //https://tools.ietf.org/html/rfc2132#section-9.13
     VendorClassIdentifier = 60,
     // https://tools.ietf.org/html/rfc2132#section-9.14
     ClientIdentifier = 61,
+    // https://tools.ietf.org/html/rfc2132#section-9.4
+    TFTPServerName = 66,
+    // https://tools.ietf.org/html/rfc2132#section-9.5
+    BootfileName = 67,
     // https://tools.ietf.org/html/rfc3004#section-4
     UserClassInfo = 77,
+    // https://tools.ietf.org/html/rfc3046#section-2
+    RelayAgentInformation = 82,
+    // https://tools.ietf.org/html/rfc4039#section-4
+    RapidCommit = 80,
     // https://tools.ietf.org/html/rfc4578#section-2.1
     ClientSystemArchitectureType = 93,
     // https://tools.ietf.org/html/rfc4578#section-2.2
@@ -117,6 +193,8 @@ pub enum DHCPOptionCode{
     ClientMachineIdentifier = 97,
     //https://tools.ietf.org/html/rfc3397#section-2
     DomainSearch = 119,
+    // https://tools.ietf.org/html/rfc3442
+    ClasslessStaticRoute = 121,
 
     //https://tools.ietf.org/html/rfc4578#section-2.4
     PXE128 = 128,
@@ -130,12 +208,16 @@ pub enum DHCPOptionCode{
 
     // 175 = Etherboot.  Undocumented.
     Etherboot = 175,
+    // Option 121 as Windows asks for it
+    MsClasslessStaticRoute = 249,
     End = 255
 }
 
 
 #[derive(Eq, Hash, PartialEq,::num_derive::FromPrimitive,::num_derive::ToPrimitive)]
 #[repr(u8)]
+#[num_traits = "num_traits"]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DHCPOptCodes {
     REQUEST = 1,
     RESPONSE = 2,
@@ -144,6 +226,8 @@ pub enum DHCPOptCodes {
 
 #[derive(Eq, Hash, PartialEq,::num_derive::FromPrimitive,::num_derive::ToPrimitive)]
 #[repr(u8)]
+#[num_traits = "num_traits"]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DHCPMessageType {
     DHCPDISCOVER = 1,
     DHCPOFFER= 2,
@@ -151,7 +235,8 @@ pub enum DHCPMessageType {
     DHCPDECLINE= 4,
     DHCPACK= 5,
     DHCPNAK= 6,
-    DHCPRELEASE= 7
+    DHCPRELEASE= 7,
+    DHCPINFORM= 8
 }
 
 
@@ -189,6 +274,14 @@ impl DHCPPacket {
         MacAddress::new(self._client_mac)
     }
 
+    /// The first hlen bytes of chaddr, which holds up to 16.
+    pub fn hardware_address(&self) -> Vec<u8>{
+        let mut chaddr = self._client_mac.to_vec();
+        chaddr.extend_from_slice(&self._client_mac_remainder);
+        chaddr.truncate(self._hw_addr_len as usize);
+        chaddr
+    }
+
     pub fn vendor_magic(&self) -> [u8; 4]  {
         let mut retval: [u8; 4] = [0; 4];
         retval.copy_from_slice(&self._vendor_magic);
@@ -233,9 +326,13 @@ impl DHCPPacket {
 
 
     pub fn dump_options(options: &HashMap::<DHCPOptionCode, VendorData>){
-        for (_code, option) in options{
+        for option in options.values(){
             println!("option code = {} len = {}",
-                     option.code as u8, option.len);
+                     option.code, option.len);
+            if option.code == DHCPOptionCode::DomainSearch as u8 {
+                println!("    domain search = {:?}",
+                         super::dns::decode_domain_list(&option.data));
+            }
         }
     }
 
@@ -266,10 +363,9 @@ impl DHCPPacket {
                             match val {
                                 Some(b) => vend_info.push(*b),
                                 None => {
-                                    vend_info.clear();
                                     println!("invalid code = {} len = {} _i={}",
                                              *code, len, _i);
-                                    break
+                                    return Err("truncated option")
                                 }
                             }
                         };
@@ -277,7 +373,7 @@ impl DHCPPacket {
                         match num::FromPrimitive::from_u8(*code) {
                             Some(m_t_c) => {vendor_data.insert(m_t_c, VendorData{
                                 code: *code,
-                                len: len,
+                                len,
                                 data: vend_info});
                             },
                             None  => {
@@ -291,9 +387,31 @@ impl DHCPPacket {
         };
         Ok(vendor_data)
     }
+
+    /// The codes of the options in the vendor area, in the order the
+    /// client put them there, unknown ones included
+    pub fn option_codes(&self) -> Vec<u8> {
+        let mut codes = vec![];
+        let mut offset = 0;
+        while let Some(&code) = self._vendor_info.get(offset) {
+            if code == DHCPOptionCode::End as u8 {
+                break;
+            }
+            if code == DHCPOptionCode::Pad as u8 {
+                offset += 1;
+                continue;
+            }
+            codes.push(code);
+            offset += 2 + self._vendor_info.get(offset + 1).copied().unwrap_or(0) as usize;
+        }
+        codes
+    }
 }
 
 #[cfg(test)]
+// The tests are older than the lints
+#[allow(clippy::assertions_on_constants, clippy::let_and_return, clippy::manual_memcpy,
+        clippy::unused_io_amount, clippy::useless_vec, non_fmt_panics)]
 mod tests {
     use std::env;
     use std::fs::File;
@@ -512,4 +630,34 @@ mod tests {
         }
     }
 
+
+    #[test]
+    fn test_option_codes(){
+        let packet = read_discovery_packet();
+        assert_eq!(vec![53, 57, 93, 94, 60, 77, 55, 175, 61, 97], packet.option_codes());
+    }
+
+    #[test]
+    fn test_client_id(){
+        let packet = read_discovery_packet();
+        let mut options = packet.parse_vendor_data().unwrap();
+        options.remove(&DHCPOptionCode::ClientIdentifier);
+        let client_id = ClientId::from_packet(&packet, &options);
+        assert_eq!(ClientId(vec![1, 0x52, 0x54, 0, 0x94, 0x9e, 0xf2]), client_id);
+        assert_eq!(Some(packet.client_mac()), client_id.mac());
+        assert_eq!("01:52:54:00:94:9e:f2", client_id.to_string());
+
+        // InfiniBand leaves chaddr empty and sends option 61
+        let mut packet = DHCPPacket::new();
+        packet._hwtype = 32;
+        assert!(ClientId::from_packet(&packet, &HashMap::new()).is_empty());
+        let mut options = HashMap::new();
+        options.insert(DHCPOptionCode::ClientIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientIdentifier,
+                                       &[0xff, 0, 0, 0, 1]).unwrap());
+        let client_id = ClientId::from_packet(&packet, &options);
+        assert_eq!(ClientId(vec![0xff, 0, 0, 0, 1]), client_id);
+        assert_eq!(None, client_id.mac());
+        assert!(!client_id.is_empty());
+    }
 }
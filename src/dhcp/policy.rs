@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use mac_address::MacAddress;
+
+use crate::config::parse_hex;
+use crate::config::ServerConfig;
+
+use super::packet::ClientId;
+use super::packet::DHCPOptionCode;
+use super::packet::DHCPPacket;
+use super::packet::VendorData;
+
+/// A machine that is network booting: a PXE or UEFI HTTP Boot client,
+/// or iPXE.
+pub fn is_boot_client(options: &HashMap::<DHCPOptionCode, VendorData>) -> bool {
+    super::pxe::is_pxe_client(options) || super::is_http_boot_client(options)
+        || super::is_ipxe_client(options)
+}
+
+/// An entry in the allow and deny lists is a whole MAC address or the
+/// first bytes of one, such as an OUI.
+pub fn matches_mac_list(list: &[String], mac: Option<MacAddress>) -> bool {
+    match mac {
+        Some(mac) => list.iter().any(|entry| match parse_hex(entry) {
+            Ok(prefix) => mac.bytes().starts_with(&prefix),
+            Err(_) => false
+        }),
+        None => false
+    }
+}
+
+/// Whether the server should stay silent: the client is denied, is not
+/// on a non-empty allow list, or is not network booting in PXE-only
+/// mode.  The MAC address is the one in the client identifier, or
+/// chaddr when the identifier is not a MAC address.
+pub fn is_ignored(config: &ServerConfig, packet: &DHCPPacket,
+                  options: &HashMap::<DHCPOptionCode, VendorData>) -> bool {
+    let mac = ClientId::from_packet(packet, options).mac().
+        or_else(|| if packet._hw_addr_len == 6 { Some(packet.client_mac()) } else { None });
+    if matches_mac_list(&config.deny, mac) {
+        return true;
+    }
+    if !config.allow.is_empty() && !matches_mac_list(&config.allow, mac) {
+        return true;
+    }
+    config.pxe_only && !is_boot_client(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> DHCPPacket {
+        let mut packet = DHCPPacket::new();
+        packet._hwtype = 1;
+        packet._hw_addr_len = 6;
+        packet._client_mac = [0x52, 0x54, 0, 0x94, 0x9e, 0xf2];
+        packet
+    }
+
+    fn pxe_options() -> HashMap::<DHCPOptionCode, VendorData> {
+        let mut options = HashMap::new();
+        options.insert(DHCPOptionCode::VendorClassIdentifier,
+                       VendorData::new(DHCPOptionCode::VendorClassIdentifier,
+                                       b"PXEClient:Arch:00000").unwrap());
+        options
+    }
+
+    #[test]
+    fn test_pxe_only() {
+        let config = ServerConfig { pxe_only: true, ..ServerConfig::default() };
+        assert!(is_ignored(&config, &packet(), &HashMap::new()));
+        assert!(!is_ignored(&config, &packet(), &pxe_options()));
+        assert!(!is_ignored(&ServerConfig::default(), &packet(), &HashMap::new()));
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists() {
+        let config = ServerConfig {
+            allow: vec!["52:54:00".to_string()],
+            deny: vec!["52:54:00:94:9e:f2".to_string()],
+            ..ServerConfig::default()
+        };
+        assert!(is_ignored(&config, &packet(), &pxe_options()));
+        let mut other = packet();
+        other._client_mac[5] = 1;
+        assert!(!is_ignored(&config, &other, &pxe_options()));
+        other._client_mac[0] = 0;
+        assert!(is_ignored(&config, &other, &pxe_options()));
+    }
+
+    #[test]
+    fn test_client_identifier_without_mac() {
+        let config = ServerConfig {
+            deny: vec!["52:54:00:94:9e:f2".to_string()],
+            ..ServerConfig::default()
+        };
+        // An RFC 4361 identifier: type 255, IAID and DUID
+        let mut options = pxe_options();
+        options.insert(DHCPOptionCode::ClientIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientIdentifier,
+                                       &[255, 0, 0, 0, 1, 0, 4, 1, 2, 3, 4]).unwrap());
+        assert!(is_ignored(&config, &packet(), &options));
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use super::packet::DHCPOptionCode;
+use super::packet::DHCPPacket;
+use super::packet::VendorData;
+
+// See the Preboot Execution Environment (PXE) Specification, version 2.1,
+// section 2.2 for what a DHCP server owes a PXEClient.
+
+pub const PXE_CLIENT: &str = "PXEClient";
+
+// PXE vendor sub-option 6 tells the client whether to do PXE boot server
+// discovery.  Bit 3 says to skip it and use the boot file in the offer.
+const PXE_DISCOVERY_CONTROL: u8 = 6;
+const USE_BOOT_FILE: u8 = 0x08;
+
+/// A PXE client sends a vendor class of the form
+/// "PXEClient:Arch:xxxxx:UNDI:yyyzzz".
+pub fn is_pxe_client(options: &HashMap::<DHCPOptionCode, VendorData>) -> bool {
+    match options.get(&DHCPOptionCode::VendorClassIdentifier){
+        Some(option) => option.data.starts_with(PXE_CLIENT.as_bytes()),
+        None => false
+    }
+}
+
+/// The machine UUID from option 97: a type byte of 0 followed by the 16
+/// byte UUID.  Anything else is ignored.
+pub fn machine_uuid(options: &HashMap::<DHCPOptionCode, VendorData>) -> Option<[u8; 16]> {
+    match options.get(&DHCPOptionCode::ClientMachineIdentifier){
+        Some(option) if option.data.len() == 17 && option.data[0] == 0 => {
+            let mut uuid = [0; 16];
+            uuid.copy_from_slice(&option.data[1..]);
+            Some(uuid)
+        },
+        _ => None
+    }
+}
+
+/// SMBIOS stores the first three UUID fields little endian, and firmware
+/// does not agree on whether option 97 keeps that order.  This gives the
+/// other order, so both can be matched.
+pub fn swap_uuid_fields(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut swapped = *uuid;
+    swapped[0..4].reverse();
+    swapped[4..6].reverse();
+    swapped[6..8].reverse();
+    swapped
+}
+
+/// Adds the options a PXEClient looks for before it accepts a reply:
+/// the "PXEClient" vendor class, its own UUID, and PXE vendor options
+/// telling it to boot the file in the reply without boot server
+/// discovery.
+pub fn add_pxe_options(options: &HashMap::<DHCPOptionCode, VendorData>,
+                       vendor_data: &mut Vec::<VendorData>) -> Result<(), &'static str>{
+    vendor_data.push(VendorData::new(DHCPOptionCode::VendorClassIdentifier,
+        PXE_CLIENT.as_bytes())?);
+
+    if let Some(uuid) = machine_uuid(options){
+        let mut data = vec![0];
+        data.extend_from_slice(&uuid);
+        vendor_data.push(VendorData::new(
+            DHCPOptionCode::ClientMachineIdentifier, &data)?);
+    }
+
+    vendor_data.push(VendorData::new(DHCPOptionCode::VendorSpecificInformation,
+        &[PXE_DISCOVERY_CONTROL, 1, USE_BOOT_FILE, DHCPOptionCode::End as u8])?);
+    Ok(())
+}
+
+/// siaddr has to name the server the boot file is fetched from.  sname
+/// may be empty, but if it is used it must hold a null terminated name:
+/// PXE clients do not accept option overload in that field.
+pub fn set_pxe_fields(boot_server: Ipv4Addr, response_packet: &mut DHCPPacket){
+    response_packet._server_ip = boot_server.octets();
+    let last = response_packet._server_host_name.len() - 1;
+    response_packet._server_host_name[last] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options_with_vendor_class(vendor_class: &[u8]) ->
+        HashMap::<DHCPOptionCode, VendorData>
+    {
+        let mut options = HashMap::new();
+        options.insert(DHCPOptionCode::VendorClassIdentifier,
+                       VendorData::new(DHCPOptionCode::VendorClassIdentifier,
+                                       vendor_class).unwrap());
+        options
+    }
+
+    #[test]
+    fn test_is_pxe_client() {
+        assert!(is_pxe_client(&options_with_vendor_class(
+            b"PXEClient:Arch:00000:UNDI:002001")));
+        assert!(!is_pxe_client(&options_with_vendor_class(b"MSFT 5.0")));
+        assert!(!is_pxe_client(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_uuid_is_echoed() {
+        let mut options = options_with_vendor_class(b"PXEClient");
+        let uuid = [0, 178, 35, 76, 56, 225, 195, 173, 69, 183, 151, 210, 221,
+                    34, 14, 27, 157];
+        options.insert(DHCPOptionCode::ClientMachineIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientMachineIdentifier,
+                                       &uuid).unwrap());
+        let mut vendor_data = vec![];
+        add_pxe_options(&options, &mut vendor_data).unwrap();
+        assert_eq!(3, vendor_data.len());
+        assert_eq!(b"PXEClient".to_vec(), vendor_data[0].data);
+        assert_eq!(uuid.to_vec(), vendor_data[1].data);
+        assert_eq!(vec![6, 1, 8, 255], vendor_data[2].data);
+    }
+
+    #[test]
+    fn test_malformed_uuid_is_dropped() {
+        let mut options = options_with_vendor_class(b"PXEClient");
+        options.insert(DHCPOptionCode::ClientMachineIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientMachineIdentifier,
+                                       &[1, 2, 3]).unwrap());
+        let mut vendor_data = vec![];
+        add_pxe_options(&options, &mut vendor_data).unwrap();
+        assert_eq!(2, vendor_data.len());
+    }
+
+    #[test]
+    fn test_machine_uuid() {
+        let mut options = HashMap::new();
+        assert_eq!(None, machine_uuid(&options));
+        let mut data = vec![0];
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+        options.insert(DHCPOptionCode::ClientMachineIdentifier,
+                       VendorData::new(DHCPOptionCode::ClientMachineIdentifier, &data).unwrap());
+        let uuid = machine_uuid(&options).unwrap();
+        assert_eq!([4, 3, 2, 1, 6, 5, 8, 7, 9, 10, 11, 12, 13, 14, 15, 16],
+                   swap_uuid_fields(&uuid));
+        assert_eq!(uuid, swap_uuid_fields(&swap_uuid_fields(&uuid)));
+    }
+}
@@ -0,0 +1,55 @@
+use std::net::Ipv4Addr;
+
+// Classless static routes use the encoding of RFC 3442 section 1: the
+// prefix length, only the significant octets of the destination, then
+// the router.  Microsoft clients ask for the same data in option 249.
+
+/// A route to destination/prefix through gateway
+pub type Route = (Ipv4Addr, u8, Ipv4Addr);
+
+pub fn encode_routes(routes: &[Route]) -> Result<Vec<u8>, &'static str> {
+    let mut buf: Vec<u8> = vec![];
+    for (destination, prefix, gateway) in routes {
+        if *prefix > 32 {
+            return Err("bad route prefix length");
+        }
+        let significant = (*prefix as usize).div_ceil(8);
+        let masked = u32::from(*destination) & u32::from(crate::config::prefix_mask(*prefix));
+        buf.push(*prefix);
+        buf.extend_from_slice(&masked.to_be_bytes()[..significant]);
+        buf.extend_from_slice(&gateway.octets());
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_rfc3442_examples() {
+        let router = Ipv4Addr::new(10, 0, 0, 1);
+        let cases: Vec<(Route, Vec<u8>)> = vec![
+            ((Ipv4Addr::new(0, 0, 0, 0), 0, router), vec![0, 10, 0, 0, 1]),
+            ((Ipv4Addr::new(10, 0, 0, 0), 8, router), vec![8, 10, 10, 0, 0, 1]),
+            ((Ipv4Addr::new(10, 17, 0, 0), 16, router), vec![16, 10, 17, 10, 0, 0, 1]),
+            ((Ipv4Addr::new(10, 27, 129, 0), 24, router),
+             vec![24, 10, 27, 129, 10, 0, 0, 1]),
+            ((Ipv4Addr::new(10, 229, 0, 128), 25, router),
+             vec![25, 10, 229, 0, 128, 10, 0, 0, 1]),
+            ((Ipv4Addr::new(10, 198, 122, 47), 32, router),
+             vec![32, 10, 198, 122, 47, 10, 0, 0, 1]),
+        ];
+        for (route, expected) in cases {
+            assert_eq!(expected, encode_routes(&[route]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_host_bits_are_dropped() {
+        let route = (Ipv4Addr::new(10, 9, 255, 1), 12, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(vec![12, 10, 0, 10, 0, 0, 1], encode_routes(&[route]).unwrap());
+        let route = (Ipv4Addr::new(10, 9, 0, 0), 33, Ipv4Addr::new(10, 0, 0, 1));
+        assert!(encode_routes(&[route]).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::packet::DHCPOptCodes;
+use super::packet::DHCPPacket;
+use super::packet::VENDOR_MAGIC;
+
+// The fixed BOOTP header, everything before the vendor magic
+pub const MIN_PACKET_LEN: usize = 236;
+// RFC 1542 section 4.1.1 puts the largest useful hop count at 16
+pub const MAX_HOPS: u8 = 16;
+// chaddr is 16 bytes
+pub const MAX_HW_ADDR_LEN: u8 = 16;
+const HWTYPE_ETHERNET: u8 = 1;
+const ETHERNET_ADDR_LEN: u8 = 6;
+
+/// Why a datagram was dropped without an answer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PacketError {
+    Truncated(usize),
+    NotARequest(u8),
+    BadHardwareLength(u8),
+    TooManyHops(u8),
+    BadMagic([u8; 4]),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::Truncated(len) =>
+                write!(f, "truncated packet of {} bytes", len),
+            PacketError::NotARequest(opcode) =>
+                write!(f, "opcode {} is not a BOOTREQUEST", opcode),
+            PacketError::BadHardwareLength(len) =>
+                write!(f, "bad hardware address length {}", len),
+            PacketError::TooManyHops(hops) =>
+                write!(f, "hop count {} is over the relay limit", hops),
+            PacketError::BadMagic(magic) =>
+                write!(f, "bad vendor magic {:?}", magic),
+        }
+    }
+}
+
+/// Checks the fixed header of a datagram of len bytes before anything
+/// in it is trusted.  A zero magic is plain RFC 951 BOOTP.
+pub fn validate(packet: &DHCPPacket, len: usize) -> Result<(), PacketError> {
+    if len < MIN_PACKET_LEN {
+        return Err(PacketError::Truncated(len));
+    }
+    if packet.opcode != DHCPOptCodes::REQUEST as u8 {
+        return Err(PacketError::NotARequest(packet.opcode));
+    }
+    if packet._hw_addr_len > MAX_HW_ADDR_LEN ||
+        (packet._hwtype == HWTYPE_ETHERNET && packet._hw_addr_len != ETHERNET_ADDR_LEN) {
+        return Err(PacketError::BadHardwareLength(packet._hw_addr_len));
+    }
+    if packet._hop_count > MAX_HOPS {
+        return Err(PacketError::TooManyHops(packet._hop_count));
+    }
+    let magic = packet.vendor_magic();
+    if len > MIN_PACKET_LEN && magic != VENDOR_MAGIC && magic != [0; 4] {
+        return Err(PacketError::BadMagic(magic));
+    }
+    Ok(())
+}
+
+/// What happened to the datagrams the server received
+#[derive(Default)]
+pub struct PacketCounters {
+    pub received: AtomicU64,
+    pub answered: AtomicU64,
+    pub failed: AtomicU64,
+    /// Valid requests that policy says not to answer
+    pub ignored: AtomicU64,
+    pub truncated: AtomicU64,
+    pub not_a_request: AtomicU64,
+    pub bad_hardware_length: AtomicU64,
+    pub too_many_hops: AtomicU64,
+    pub bad_magic: AtomicU64,
+}
+
+impl PacketCounters {
+    pub fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, error: &PacketError) {
+        PacketCounters::count(match error {
+            PacketError::Truncated(_) => &self.truncated,
+            PacketError::NotARequest(_) => &self.not_a_request,
+            PacketError::BadHardwareLength(_) => &self.bad_hardware_length,
+            PacketError::TooManyHops(_) => &self.too_many_hops,
+            PacketError::BadMagic(_) => &self.bad_magic,
+        });
+    }
+
+    pub fn log(&self) {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        println!("packets received={} answered={} failed={} ignored={} truncated={} \
+                  not_a_request={} bad_hardware_length={} too_many_hops={} bad_magic={}",
+                 get(&self.received), get(&self.answered), get(&self.failed),
+                 get(&self.ignored), get(&self.truncated), get(&self.not_a_request),
+                 get(&self.bad_hardware_length), get(&self.too_many_hops),
+                 get(&self.bad_magic));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    fn request() -> DHCPPacket {
+        let mut packet = DHCPPacket::new();
+        packet.opcode = DHCPOptCodes::REQUEST as u8;
+        packet._hwtype = HWTYPE_ETHERNET;
+        packet._hw_addr_len = ETHERNET_ADDR_LEN;
+        packet._vendor_magic = VENDOR_MAGIC;
+        packet
+    }
+
+    #[test]
+    fn test_valid_request() {
+        assert_eq!(Ok(()), validate(&request(), size_of::<DHCPPacket>()));
+        let mut packet = request();
+        packet._vendor_magic = [0; 4];
+        assert_eq!(Ok(()), validate(&packet, 300));
+        // A bare header has no room for a magic
+        packet._vendor_magic = [1, 2, 3, 4];
+        assert_eq!(Ok(()), validate(&packet, MIN_PACKET_LEN));
+    }
+
+    #[test]
+    fn test_rejected_headers() {
+        let len = size_of::<DHCPPacket>();
+        assert_eq!(Err(PacketError::Truncated(20)), validate(&request(), 20));
+
+        let mut packet = request();
+        packet.opcode = DHCPOptCodes::RESPONSE as u8;
+        assert_eq!(Err(PacketError::NotARequest(2)), validate(&packet, len));
+
+        let mut packet = request();
+        packet._hw_addr_len = 17;
+        assert_eq!(Err(PacketError::BadHardwareLength(17)), validate(&packet, len));
+        packet._hw_addr_len = 8;
+        assert_eq!(Err(PacketError::BadHardwareLength(8)), validate(&packet, len));
+        packet._hwtype = 32;
+        assert_eq!(Ok(()), validate(&packet, len));
+
+        let mut packet = request();
+        packet._hop_count = 17;
+        assert_eq!(Err(PacketError::TooManyHops(17)), validate(&packet, len));
+
+        let mut packet = request();
+        packet._vendor_magic = [1, 2, 3, 4];
+        assert_eq!(Err(PacketError::BadMagic([1, 2, 3, 4])), validate(&packet, len));
+    }
+
+    #[test]
+    fn test_counters() {
+        let counters = PacketCounters::default();
+        counters.dropped(&PacketError::TooManyHops(20));
+        counters.dropped(&PacketError::TooManyHops(30));
+        counters.dropped(&PacketError::Truncated(0));
+        assert_eq!(2, counters.too_many_hops.load(Ordering::Relaxed));
+        assert_eq!(1, counters.truncated.load(Ordering::Relaxed));
+        assert_eq!(0, counters.bad_magic.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Error;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use mac_address::MacAddress;
+
+mod packet;
+use packet::DHCPv6MessageType;
+use packet::DHCPv6Option;
+use packet::DHCPv6OptionCode;
+use packet::DHCPv6Packet;
+use packet::Duid;
+use packet::IaAddress;
+use packet::IaNa;
+use packet::StatusCode;
+
+use crate::config::HostConfig;
+use crate::config::ServerConfig;
+use crate::provider;
+use crate::provider::HostKey;
+use crate::provider::HostProvider;
+use crate::state::StateStore;
+use crate::token;
+use crate::token::TokenSigner;
+
+// https://tools.ietf.org/html/rfc8415#section-7.1
+const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: Ipv6Addr =
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+const SERVER_PORT: u16 = 547;
+
+// How long an address offered in an Advertise is held for the client
+const OFFER_HOLD_SECS: u64 = 60;
+
+// The enterprise number UEFI HTTP Boot clients put in their vendor class
+const HTTP_CLIENT_ENTERPRISE: u32 = 343;
+const HTTP_CLIENT: &[u8] = b"HTTPClient";
+const IPXE_USER_CLASS: &[u8] = b"iPXE";
+
+struct Lease {
+    addr: Ipv6Addr,
+    expires: SystemTime,
+}
+
+pub struct DHCPv6Server {
+    config: ServerConfig,
+    provider: Arc<dyn HostProvider>,
+    tokens: Arc<TokenSigner>,
+    server_duid: Duid,
+    logging: bool,
+    leases: Mutex<HashMap<(Duid, u32), Lease>>,
+}
+
+impl DHCPv6Server {
+    pub fn new(config: ServerConfig, states: Arc<StateStore>, tokens: Arc<TokenSigner>,
+               logging: bool) -> Result<DHCPv6Server, Error> {
+        let mac = match mac_address::get_mac_address() {
+            Ok(Some(mac)) => mac,
+            _ => MacAddress::new([0; 6])
+        };
+        Ok(DHCPv6Server {
+            provider: provider::from_config(&config, states)?,
+            config,
+            tokens,
+            server_duid: Duid::from_mac(&mac),
+            logging,
+            leases: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Finds the host entry for a client, by DUID or by the MAC address
+    /// in a link-layer DUID.  These are the same hosts the DHCPv4 server
+    /// answers for.
+    fn host(&self, client_id: &Duid) -> Option<HostConfig> {
+        let key = HostKey {
+            mac: client_id.mac(),
+            duid: Some(client_id.0.clone()),
+            ..HostKey::default()
+        };
+        match self.provider.lookup(&key) {
+            Ok(host) => host,
+            Err(e) => {
+                println!("host lookup for {:?} failed: {}", client_id, e);
+                None
+            }
+        }
+    }
+
+    fn lifetimes(&self) -> (u32, u32) {
+        (self.config.dhcp6.preferred_lifetime, self.config.dhcp6.valid_lifetime)
+    }
+
+    /// The first address of the pool that is neither leased nor
+    /// reserved.  Hosts from providers reserve addresses outside the
+    /// pool, so only the config file's have to be skipped.
+    fn allocate(&self, leases: &HashMap<(Duid, u32), Lease>,
+                now: SystemTime) -> Option<Ipv6Addr> {
+        let used: HashSet<Ipv6Addr> = leases.values()
+            .filter(|lease| lease.expires > now)
+            .map(|lease| lease.addr)
+            .chain(self.config.hosts.iter().filter_map(|host| host.ipv6))
+            .collect();
+        let start = u128::from(self.config.dhcp6.pool_start);
+        let end = u128::from(self.config.dhcp6.pool_end);
+        (start..=end).map(Ipv6Addr::from)
+            .find(|addr| !used.contains(addr))
+    }
+
+    /// Works out the address for one IA_NA and records the binding.
+    /// Renew and Rebind only extend a binding the server already has.
+    fn bind(&self, client_id: &Duid, host: Option<&HostConfig>, ia_na: &IaNa,
+            msg_type: DHCPv6MessageType) -> IaNa {
+        let now = SystemTime::now();
+        let (preferred_lifetime, valid_lifetime) = self.lifetimes();
+        let mut leases = self.leases.lock().unwrap();
+        let key = (client_id.clone(), ia_na.iaid);
+        let existing = leases.get(&key)
+            .filter(|lease| lease.expires > now)
+            .map(|lease| lease.addr);
+        let addr = match msg_type {
+            DHCPv6MessageType::Renew | DHCPv6MessageType::Rebind => existing,
+            _ => host.and_then(|host| host.ipv6)
+                .or(existing)
+                .or_else(|| self.allocate(&leases, now))
+        };
+        let mut reply = IaNa { iaid: ia_na.iaid, t1: 0, t2: 0, options: vec![] };
+        let addr = match addr {
+            Some(addr) => addr,
+            None => {
+                let status = match msg_type {
+                    DHCPv6MessageType::Renew | DHCPv6MessageType::Rebind =>
+                        DHCPv6Option::status(StatusCode::NoBinding, "no binding"),
+                    _ => DHCPv6Option::status(StatusCode::NoAddrsAvail,
+                                              "no addresses available")
+                };
+                reply.options.push(status);
+                return reply;
+            }
+        };
+        let hold = if msg_type == DHCPv6MessageType::Solicit {
+            Duration::from_secs(OFFER_HOLD_SECS)
+        } else {
+            Duration::from_secs(valid_lifetime as u64)
+        };
+        leases.insert(key, Lease { addr, expires: now + hold });
+        reply.t1 = preferred_lifetime / 2;
+        reply.t2 = preferred_lifetime / 5 * 4;
+        reply.options.push(IaAddress { addr, preferred_lifetime, valid_lifetime }.to_option());
+        reply
+    }
+
+    fn release(&self, client_id: &Duid, ia_nas: &[IaNa]) {
+        let mut leases = self.leases.lock().unwrap();
+        for ia_na in ia_nas {
+            leases.remove(&(client_id.clone(), ia_na.iaid));
+        }
+    }
+
+    /// The RFC 5970 boot file URL.  iPXE gets its script, signed when
+    /// tokens are enabled, and UEFI HTTP Boot gets the HTTP Boot image, as
+    /// on the DHCPv4 side.
+    fn boot_file_url(&self, request: &DHCPv6Packet, client_id: &Duid,
+                     host: Option<&HostConfig>) -> Option<String> {
+        let mac = host.and_then(|host| host.mac).or_else(|| client_id.mac());
+        if request.has_class(DHCPv6OptionCode::UserClass, IPXE_USER_CLASS) {
+            if let Some(mac) = mac {
+                let url = self.config.ipxe.script_url(&mac);
+                if !self.config.tokens.enabled {
+                    return Some(url);
+                }
+                let token = self.tokens.sign(&mac, None, token::now() + self.config.tokens.lifetime_secs);
+                return Some(format!("{}?token={}", url, token));
+            }
+        }
+        if request.has_class(DHCPv6OptionCode::VendorClass, HTTP_CLIENT) {
+            return Some(self.config.http_boot.url());
+        }
+        self.config.dhcp6.boot_file_url.clone()
+    }
+
+    fn is_requested(request: &DHCPv6Packet, code: DHCPv6OptionCode) -> bool {
+        match request.option(DHCPv6OptionCode::OptionRequest) {
+            Some(option) => option.data.chunks(2)
+                .any(|chunk| chunk == (code as u16).to_be_bytes()),
+            None => false
+        }
+    }
+
+    fn add_configuration(&self, request: &DHCPv6Packet, client_id: &Duid,
+                         host: Option<&HostConfig>, reply: &mut DHCPv6Packet) {
+        let dhcp6 = &self.config.dhcp6;
+        if !dhcp6.dns_servers.is_empty() {
+            let data: Vec<u8> = dhcp6.dns_servers.iter()
+                .flat_map(|addr| addr.octets().to_vec()).collect();
+            reply.options.push(DHCPv6Option::new(DHCPv6OptionCode::DNSServers, &data));
+        }
+        if request.has_class(DHCPv6OptionCode::VendorClass, HTTP_CLIENT) {
+            let mut data = HTTP_CLIENT_ENTERPRISE.to_be_bytes().to_vec();
+            data.extend_from_slice(&(HTTP_CLIENT.len() as u16).to_be_bytes());
+            data.extend_from_slice(HTTP_CLIENT);
+            reply.options.push(DHCPv6Option::new(DHCPv6OptionCode::VendorClass, &data));
+        }
+        if DHCPv6Server::is_requested(request, DHCPv6OptionCode::BootfileUrl) {
+            if let Some(url) = self.boot_file_url(request, client_id, host) {
+                reply.options.push(DHCPv6Option::new(DHCPv6OptionCode::BootfileUrl,
+                                                     url.as_bytes()));
+            }
+        }
+        if DHCPv6Server::is_requested(request, DHCPv6OptionCode::BootfileParam)
+            && !dhcp6.boot_file_params.is_empty()
+        {
+            let mut data = vec![];
+            for param in &dhcp6.boot_file_params {
+                data.extend_from_slice(&(param.len() as u16).to_be_bytes());
+                data.extend_from_slice(param.as_bytes());
+            }
+            reply.options.push(DHCPv6Option::new(DHCPv6OptionCode::BootfileParam, &data));
+        }
+    }
+
+    /// Returns None for messages that are meant for another server.
+    pub fn generate_response(&self, request: &DHCPv6Packet) ->
+        Result<Option<DHCPv6Packet>, &'static str>
+    {
+        let msg_type = match request.message_type() {
+            Some(msg_type) => msg_type,
+            None => return Err("unknown message type")
+        };
+        let client_id = match request.client_id() {
+            Some(client_id) => client_id,
+            None => return Err("missing client identifier")
+        };
+        match msg_type {
+            DHCPv6MessageType::Solicit => {
+                if request.has_option(DHCPv6OptionCode::ServerId) {
+                    return Err("solicit carries a server identifier");
+                }
+            },
+            DHCPv6MessageType::Request | DHCPv6MessageType::Renew |
+            DHCPv6MessageType::Release => {
+                if request.server_id().as_ref() != Some(&self.server_duid) {
+                    return Ok(None);
+                }
+            },
+            DHCPv6MessageType::Rebind => (),
+            _ => return Err("cannot handle request for type")
+        }
+
+        let reply_type = if msg_type == DHCPv6MessageType::Solicit {
+            DHCPv6MessageType::Advertise
+        } else {
+            DHCPv6MessageType::Reply
+        };
+        let mut reply = DHCPv6Packet::new(reply_type, request.txn_id);
+        reply.options.push(DHCPv6Option::new(DHCPv6OptionCode::ClientId, &client_id.0));
+        reply.options.push(DHCPv6Option::new(DHCPv6OptionCode::ServerId,
+                                             &self.server_duid.0));
+        let ia_nas = request.ia_nas()?;
+        if msg_type == DHCPv6MessageType::Release {
+            self.release(&client_id, &ia_nas);
+            reply.options.push(DHCPv6Option::status(StatusCode::Success, "released"));
+            return Ok(Some(reply));
+        }
+
+        let host = self.host(&client_id);
+        let host = host.as_ref();
+        for ia_na in &ia_nas {
+            reply.options.push(self.bind(&client_id, host, ia_na, msg_type).to_option());
+        }
+        self.add_configuration(request, &client_id, host, &mut reply);
+        Ok(Some(reply))
+    }
+
+    fn handle_packet(&self, socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<()> {
+        let (amt, src) = socket.recv_from(buf)?;
+        let request = match DHCPv6Packet::parse(&buf[..amt]) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("Bad DHCPv6 packet from {}: {}", src, e);
+                return Ok(());
+            }
+        };
+        if self.logging {
+            println!("DHCPv6 packet received from {}", src);
+            request.log();
+        }
+        match self.generate_response(&request) {
+            Ok(Some(reply)) => {
+                if self.logging {
+                    println!("sending DHCPv6 packet");
+                    reply.log();
+                }
+                socket.send_to(&reply.to_bytes(), src)?;
+            },
+            Ok(None) => (),
+            Err(e) => println!("DHCPv6 request from {} not answered: {}", src, e)
+        }
+        Ok(())
+    }
+
+    pub fn run(&self) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, SERVER_PORT)))?;
+        socket.join_multicast_v6(&ALL_DHCP_RELAY_AGENTS_AND_SERVERS, 0)?;
+        let mut buf = [0u8; 1500];
+        // One bad packet must not take the server down
+        loop {
+            if let Err(e) = self.handle_packet(&socket, &mut buf) {
+                println!("error handling DHCPv6 packet: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2];
+
+    fn make_test_server(config: &str) -> DHCPv6Server {
+        let mut server = DHCPv6Server::new(ServerConfig::parse(config).unwrap(), Arc::default(),
+                                           Arc::default(), false).unwrap();
+        server.server_duid = Duid::from_mac(&MacAddress::new([2, 0, 0, 0, 0, 1]));
+        server
+    }
+
+    fn make_request(msg_type: DHCPv6MessageType, server: Option<&Duid>) -> DHCPv6Packet {
+        let mut request = DHCPv6Packet::new(msg_type, [0xa, 0xb, 0xc]);
+        request.options.push(DHCPv6Option::new(
+            DHCPv6OptionCode::ClientId, &Duid::from_mac(&MacAddress::new(CLIENT_MAC)).0));
+        if let Some(server_duid) = server {
+            request.options.push(DHCPv6Option::new(DHCPv6OptionCode::ServerId,
+                                                   &server_duid.0));
+        }
+        request.options.push(IaNa { iaid: 1, t1: 0, t2: 0, options: vec![] }.to_option());
+        request.options.push(DHCPv6Option::new(DHCPv6OptionCode::OptionRequest,
+                                               &[0, 23, 0, 59, 0, 60]));
+        request
+    }
+
+    fn leased_address(reply: &DHCPv6Packet) -> Option<Ipv6Addr> {
+        reply.ia_nas().unwrap()[0].addresses().first().map(|address| address.addr)
+    }
+
+    #[test]
+    fn test_solicit_request_renew_release() {
+        let server = make_test_server(
+            "[dhcp6]\n\
+             pool_start = \"fd00::10\"\n\
+             pool_end = \"fd00::11\"\n\
+             boot_file_url = \"tftp://[fd00::1]/bootx64.efi\"\n\
+             boot_file_params = [\"console=ttyS0\"]\n");
+        let advertise = server.generate_response(
+            &make_request(DHCPv6MessageType::Solicit, None)).unwrap().unwrap();
+        assert_eq!(DHCPv6MessageType::Advertise as u8, advertise.msg_type);
+        assert_eq!([0xa, 0xb, 0xc], advertise.txn_id);
+        assert_eq!(Some(&server.server_duid), advertise.server_id().as_ref());
+        let addr = leased_address(&advertise).unwrap();
+        assert_eq!("fd00::10".parse::<Ipv6Addr>().unwrap(), addr);
+        assert_eq!(b"tftp://[fd00::1]/bootx64.efi".to_vec(),
+                   advertise.option(DHCPv6OptionCode::BootfileUrl).unwrap().data);
+        assert_eq!(b"\x00\x0dconsole=ttyS0".to_vec(),
+                   advertise.option(DHCPv6OptionCode::BootfileParam).unwrap().data);
+
+        let reply = server.generate_response(&make_request(
+            DHCPv6MessageType::Request, Some(&server.server_duid))).unwrap().unwrap();
+        assert_eq!(DHCPv6MessageType::Reply as u8, reply.msg_type);
+        assert_eq!(Some(addr), leased_address(&reply));
+        let ia_na = &reply.ia_nas().unwrap()[0];
+        assert_eq!((1800, 2880), (ia_na.t1, ia_na.t2));
+
+        let reply = server.generate_response(&make_request(
+            DHCPv6MessageType::Renew, Some(&server.server_duid))).unwrap().unwrap();
+        assert_eq!(Some(addr), leased_address(&reply));
+
+        server.generate_response(&make_request(
+            DHCPv6MessageType::Release, Some(&server.server_duid))).unwrap().unwrap();
+        let reply = server.generate_response(&make_request(
+            DHCPv6MessageType::Renew, Some(&server.server_duid))).unwrap().unwrap();
+        assert_eq!(None, leased_address(&reply));
+        assert_eq!(Some(StatusCode::NoBinding as u16), reply.ia_nas().unwrap()[0].status());
+    }
+
+    #[test]
+    fn test_request_for_other_server_is_ignored() {
+        let server = make_test_server("");
+        let other = Duid::from_mac(&MacAddress::new([2, 0, 0, 0, 0, 2]));
+        assert!(server.generate_response(
+            &make_request(DHCPv6MessageType::Request, Some(&other))).unwrap().is_none());
+        assert!(server.generate_response(
+            &make_request(DHCPv6MessageType::Solicit, Some(&other))).is_err());
+    }
+
+    #[test]
+    fn test_pool_exhausted() {
+        let server = make_test_server(
+            "[dhcp6]\n\
+             pool_start = \"fd00::10\"\n\
+             pool_end = \"fd00::10\"\n\
+             [[hosts]]\n\
+             mac = \"00:11:22:33:44:55\"\n\
+             ipv6 = \"fd00::10\"\n");
+        let advertise = server.generate_response(
+            &make_request(DHCPv6MessageType::Solicit, None)).unwrap().unwrap();
+        assert_eq!(None, leased_address(&advertise));
+        assert_eq!(Some(StatusCode::NoAddrsAvail as u16),
+                   advertise.ia_nas().unwrap()[0].status());
+    }
+
+    #[test]
+    fn test_host_reservation_and_ipxe() {
+        let server = make_test_server(
+            "[[hosts]]\n\
+             mac = \"00:11:22:33:44:55\"\n\
+             duid = \"00:03:00:01:52:54:00:94:9e:f2\"\n\
+             ipv6 = \"fd00::99\"\n");
+        let mut solicit = make_request(DHCPv6MessageType::Solicit, None);
+        solicit.options.push(DHCPv6Option::new(DHCPv6OptionCode::UserClass,
+                                               b"\x00\x04iPXE"));
+        let advertise = server.generate_response(&solicit).unwrap().unwrap();
+        assert_eq!(Some("fd00::99".parse().unwrap()), leased_address(&advertise));
+        assert_eq!(b"http://192.168.144.1:8080/ipxe/00:11:22:33:44:55".to_vec(),
+                   advertise.option(DHCPv6OptionCode::BootfileUrl).unwrap().data);
+    }
+
+    #[test]
+    fn test_approved_host() {
+        let states = Arc::new(StateStore::default());
+        let mac = MacAddress::new(CLIENT_MAC);
+        states.report_facts(&mac, Default::default(), 10).unwrap();
+        let host = HostConfig { mac: Some(mac), ipv6: Some("fd00::42".parse().unwrap()),
+                                ..HostConfig::default() };
+        states.approve(&mac, Some(host)).unwrap();
+        let server = DHCPv6Server::new(ServerConfig::default(), states, Arc::default(),
+                                       false).unwrap();
+        let advertise = server.generate_response(
+            &make_request(DHCPv6MessageType::Solicit, None)).unwrap().unwrap();
+        assert_eq!(Some("fd00::42".parse().unwrap()), leased_address(&advertise));
+    }
+
+    #[test]
+    fn test_script_url_carries_token() {
+        let mut config = ServerConfig::default();
+        config.tokens.enabled = true;
+        let tokens = Arc::new(TokenSigner::generate().unwrap());
+        let server = DHCPv6Server::new(config, Arc::default(), tokens.clone(), false).unwrap();
+        let mut solicit = make_request(DHCPv6MessageType::Solicit, None);
+        solicit.options.push(DHCPv6Option::new(DHCPv6OptionCode::UserClass,
+                                               b"\x00\x04iPXE"));
+        let advertise = server.generate_response(&solicit).unwrap().unwrap();
+        let url = String::from_utf8(
+            advertise.option(DHCPv6OptionCode::BootfileUrl).unwrap().data.clone()).unwrap();
+        let (url, token) = url.split_once("?token=").unwrap();
+        assert_eq!("http://192.168.144.1:8080/ipxe/52:54:00:94:9e:f2", url);
+        let mac = MacAddress::new(CLIENT_MAC);
+        assert_eq!(Ok(()), tokens.verify(token, &mac, None, token::now()));
+    }
+}
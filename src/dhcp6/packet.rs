@@ -0,0 +1,389 @@
+use std::convert::TryInto;
+use std::net::Ipv6Addr;
+
+use mac_address::MacAddress;
+
+// https://tools.ietf.org/html/rfc8415#section-7.3
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ::num_derive::FromPrimitive)]
+#[repr(u8)]
+#[num_traits = "num_traits"]
+pub enum DHCPv6MessageType {
+    Solicit = 1,
+    Advertise = 2,
+    Request = 3,
+    Confirm = 4,
+    Renew = 5,
+    Rebind = 6,
+    Reply = 7,
+    Release = 8,
+    Decline = 9,
+    Reconfigure = 10,
+    InformationRequest = 11,
+    RelayForw = 12,
+    RelayRepl = 13,
+}
+
+// https://www.iana.org/assignments/dhcpv6-parameters/dhcpv6-parameters.xhtml
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ::num_derive::FromPrimitive)]
+#[repr(u16)]
+#[num_traits = "num_traits"]
+pub enum DHCPv6OptionCode {
+    // https://tools.ietf.org/html/rfc8415#section-21
+    ClientId = 1,
+    ServerId = 2,
+    IaNa = 3,
+    IaAddr = 5,
+    OptionRequest = 6,
+    Preference = 7,
+    ElapsedTime = 8,
+    StatusCode = 13,
+    RapidCommit = 14,
+    UserClass = 15,
+    VendorClass = 16,
+    // https://tools.ietf.org/html/rfc3646#section-3
+    DNSServers = 23,
+    DomainList = 24,
+    // https://tools.ietf.org/html/rfc5970#section-3
+    BootfileUrl = 59,
+    BootfileParam = 60,
+    ClientArchType = 61,
+    NetworkInterfaceId = 62,
+}
+
+// https://tools.ietf.org/html/rfc8415#section-21.13
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ::num_derive::FromPrimitive)]
+#[repr(u16)]
+#[num_traits = "num_traits"]
+pub enum StatusCode {
+    Success = 0,
+    UnspecFail = 1,
+    NoAddrsAvail = 2,
+    NoBinding = 3,
+    NotOnLink = 4,
+    UseMulticast = 5,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DHCPv6Option {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl DHCPv6Option {
+    pub fn new(code: DHCPv6OptionCode, data: &[u8]) -> DHCPv6Option {
+        DHCPv6Option { code: code as u16, data: data.to_vec() }
+    }
+
+    pub fn status(status: StatusCode, message: &str) -> DHCPv6Option {
+        let mut data = (status as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(message.as_bytes());
+        DHCPv6Option { code: DHCPv6OptionCode::StatusCode as u16, data }
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.code.to_be_bytes());
+        buf.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+/// Options are a code and a length, both 16 bits, followed by the data.
+pub fn parse_options(mut data: &[u8]) -> Result<Vec<DHCPv6Option>, &'static str> {
+    let mut options = vec![];
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return Err("truncated DHCPv6 option header");
+        }
+        let code = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if data.len() < 4 + len {
+            return Err("truncated DHCPv6 option");
+        }
+        options.push(DHCPv6Option { code, data: data[4..4 + len].to_vec() });
+        data = &data[4 + len..];
+    }
+    Ok(options)
+}
+
+pub fn write_options(options: &[DHCPv6Option]) -> Vec<u8> {
+    let mut buf = vec![];
+    for option in options {
+        option.write(&mut buf);
+    }
+    buf
+}
+
+fn find_option(options: &[DHCPv6Option], code: DHCPv6OptionCode) -> Option<&DHCPv6Option> {
+    options.iter().find(|option| option.code == code as u16)
+}
+
+/// A client or server message.  Unlike DHCPv4 there is no fixed part
+/// beyond the message type and transaction id.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DHCPv6Packet {
+    pub msg_type: u8,
+    pub txn_id: [u8; 3],
+    pub options: Vec<DHCPv6Option>,
+}
+
+impl DHCPv6Packet {
+    pub fn new(msg_type: DHCPv6MessageType, txn_id: [u8; 3]) -> DHCPv6Packet {
+        DHCPv6Packet { msg_type: msg_type as u8, txn_id, options: vec![] }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<DHCPv6Packet, &'static str> {
+        if buf.len() < 4 {
+            return Err("DHCPv6 message too short");
+        }
+        Ok(DHCPv6Packet {
+            msg_type: buf[0],
+            txn_id: [buf[1], buf[2], buf[3]],
+            options: parse_options(&buf[4..])?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![self.msg_type];
+        buf.extend_from_slice(&self.txn_id);
+        buf.extend(write_options(&self.options));
+        buf
+    }
+
+    pub fn message_type(&self) -> Option<DHCPv6MessageType> {
+        num::FromPrimitive::from_u8(self.msg_type)
+    }
+
+    pub fn option(&self, code: DHCPv6OptionCode) -> Option<&DHCPv6Option> {
+        find_option(&self.options, code)
+    }
+
+    pub fn has_option(&self, code: DHCPv6OptionCode) -> bool {
+        self.option(code).is_some()
+    }
+
+    pub fn client_id(&self) -> Option<Duid> {
+        self.option(DHCPv6OptionCode::ClientId).map(|option| Duid(option.data.clone()))
+    }
+
+    pub fn server_id(&self) -> Option<Duid> {
+        self.option(DHCPv6OptionCode::ServerId).map(|option| Duid(option.data.clone()))
+    }
+
+    pub fn ia_nas(&self) -> Result<Vec<IaNa>, &'static str> {
+        self.options.iter()
+            .filter(|option| option.code == DHCPv6OptionCode::IaNa as u16)
+            .map(|option| IaNa::parse(&option.data))
+            .collect()
+    }
+
+    /// True if any of the class options carry the given string.  User
+    /// and vendor classes are lists of 16 bit length prefixed strings;
+    /// the vendor class starts with a 32 bit enterprise number.
+    pub fn has_class(&self, code: DHCPv6OptionCode, class: &[u8]) -> bool {
+        let data = match self.option(code) {
+            Some(option) => &option.data[..],
+            None => return false
+        };
+        let mut data = if code == DHCPv6OptionCode::VendorClass {
+            if data.len() < 4 { return false; }
+            &data[4..]
+        } else {
+            data
+        };
+        while data.len() >= 2 {
+            let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+            if data.len() < 2 + len {
+                return false;
+            }
+            if data[2..2 + len].starts_with(class) {
+                return true;
+            }
+            data = &data[2 + len..];
+        }
+        false
+    }
+
+    pub fn log(&self) {
+        println!("----------------------------------------------------");
+        println!("msg_type    = {0}", self.msg_type);
+        println!("txn_id      = {:02x}{:02x}{:02x}", self.txn_id[0],
+                 self.txn_id[1], self.txn_id[2]);
+        for option in &self.options {
+            let name: Option<DHCPv6OptionCode> = num::FromPrimitive::from_u16(option.code);
+            println!("option code = {} len = {} {:?}", option.code,
+                     option.data.len(), name);
+            if option.code == DHCPv6OptionCode::IaNa as u16 {
+                if let Ok(ia_na) = IaNa::parse(&option.data) {
+                    let status: Option<StatusCode> =
+                        ia_na.status().and_then(num::FromPrimitive::from_u16);
+                    println!("    iaid = {} addresses = {:?} status = {:?}",
+                             ia_na.iaid, ia_na.addresses(), status);
+                }
+            }
+        }
+    }
+}
+
+/// A DHCP Unique Identifier.  Clients keep the same DUID across all of
+/// their interfaces, so it is what leases are keyed by.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Duid(pub Vec<u8>);
+
+const DUID_LLT: u16 = 1;
+const DUID_LL: u16 = 3;
+const HW_TYPE_ETHERNET: u16 = 1;
+
+impl Duid {
+    pub fn from_mac(mac: &MacAddress) -> Duid {
+        let mut data = DUID_LL.to_be_bytes().to_vec();
+        data.extend_from_slice(&HW_TYPE_ETHERNET.to_be_bytes());
+        data.extend_from_slice(&mac.bytes());
+        Duid(data)
+    }
+
+    /// The Ethernet address in a link-layer DUID, if there is one.
+    pub fn mac(&self) -> Option<MacAddress> {
+        let data = &self.0;
+        if data.len() < 4 {
+            return None;
+        }
+        let duid_type = u16::from_be_bytes([data[0], data[1]]);
+        let hw_type = u16::from_be_bytes([data[2], data[3]]);
+        let link_layer = match duid_type {
+            DUID_LLT => data.get(8..),
+            DUID_LL => data.get(4..),
+            _ => None
+        }?;
+        if hw_type != HW_TYPE_ETHERNET || link_layer.len() != 6 {
+            return None;
+        }
+        Some(MacAddress::new(link_layer.try_into().ok()?))
+    }
+}
+
+/// An Identity Association for Non-temporary Addresses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IaNa {
+    pub iaid: u32,
+    pub t1: u32,
+    pub t2: u32,
+    pub options: Vec<DHCPv6Option>,
+}
+
+impl IaNa {
+    pub fn parse(data: &[u8]) -> Result<IaNa, &'static str> {
+        if data.len() < 12 {
+            return Err("truncated IA_NA");
+        }
+        Ok(IaNa {
+            iaid: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            t1: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            t2: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            options: parse_options(&data[12..])?,
+        })
+    }
+
+    pub fn to_option(&self) -> DHCPv6Option {
+        let mut data = self.iaid.to_be_bytes().to_vec();
+        data.extend_from_slice(&self.t1.to_be_bytes());
+        data.extend_from_slice(&self.t2.to_be_bytes());
+        data.extend(write_options(&self.options));
+        DHCPv6Option { code: DHCPv6OptionCode::IaNa as u16, data }
+    }
+
+    pub fn addresses(&self) -> Vec<IaAddress> {
+        self.options.iter()
+            .filter(|option| option.code == DHCPv6OptionCode::IaAddr as u16)
+            .filter_map(|option| IaAddress::parse(&option.data).ok())
+            .collect()
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        find_option(&self.options, DHCPv6OptionCode::StatusCode)
+            .filter(|option| option.data.len() >= 2)
+            .map(|option| u16::from_be_bytes([option.data[0], option.data[1]]))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IaAddress {
+    pub addr: Ipv6Addr,
+    pub preferred_lifetime: u32,
+    pub valid_lifetime: u32,
+}
+
+impl IaAddress {
+    pub fn parse(data: &[u8]) -> Result<IaAddress, &'static str> {
+        if data.len() < 24 {
+            return Err("truncated IA Address");
+        }
+        let octets: [u8; 16] = data[0..16].try_into().unwrap();
+        Ok(IaAddress {
+            addr: Ipv6Addr::from(octets),
+            preferred_lifetime: u32::from_be_bytes([data[16], data[17], data[18], data[19]]),
+            valid_lifetime: u32::from_be_bytes([data[20], data[21], data[22], data[23]]),
+        })
+    }
+
+    pub fn to_option(&self) -> DHCPv6Option {
+        let mut data = self.addr.octets().to_vec();
+        data.extend_from_slice(&self.preferred_lifetime.to_be_bytes());
+        data.extend_from_slice(&self.valid_lifetime.to_be_bytes());
+        DHCPv6Option { code: DHCPv6OptionCode::IaAddr as u16, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The hex form used in the config file
+    fn from_hex(text: &str) -> Result<Duid, &'static str> {
+        crate::config::parse_hex(text).map(Duid)
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let mut packet = DHCPv6Packet::new(DHCPv6MessageType::Solicit, [1, 2, 3]);
+        packet.options.push(DHCPv6Option::new(DHCPv6OptionCode::ElapsedTime, &[0, 0]));
+        packet.options.push(IaNa { iaid: 7, t1: 0, t2: 0, options: vec![] }.to_option());
+        let bytes = packet.to_bytes();
+        assert_eq!(vec![1, 1, 2, 3, 0, 8, 0, 2, 0, 0, 0, 3, 0, 12,
+                        0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0], bytes);
+        let parsed = DHCPv6Packet::parse(&bytes).unwrap();
+        assert_eq!(packet, parsed);
+        assert_eq!(Some(DHCPv6MessageType::Solicit), parsed.message_type());
+        assert_eq!(7, parsed.ia_nas().unwrap()[0].iaid);
+    }
+
+    #[test]
+    fn test_truncated_packets() {
+        assert!(DHCPv6Packet::parse(&[1, 2, 3]).is_err());
+        assert!(DHCPv6Packet::parse(&[1, 2, 3, 4, 0, 1, 0, 9, 0]).is_err());
+        assert!(DHCPv6Packet::parse(&[1, 2, 3, 4, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_duid_mac() {
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2]);
+        assert_eq!(Some(mac), Duid::from_mac(&mac).mac());
+        let llt = from_hex("00:01:00:01:26:7f:3a:10:52:54:00:94:9e:f2").unwrap();
+        assert_eq!(Some(mac), llt.mac());
+        let en = from_hex("0002000000090c0d0e0f").unwrap();
+        assert_eq!(None, en.mac());
+        assert!(from_hex("0").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_has_class() {
+        let mut packet = DHCPv6Packet::new(DHCPv6MessageType::Solicit, [0; 3]);
+        packet.options.push(DHCPv6Option::new(DHCPv6OptionCode::UserClass,
+                                              b"\x00\x04iPXE"));
+        packet.options.push(DHCPv6Option::new(DHCPv6OptionCode::VendorClass,
+                                              b"\x00\x00\x01\x57\x00\x0aHTTPClient"));
+        assert!(packet.has_class(DHCPv6OptionCode::UserClass, b"iPXE"));
+        assert!(packet.has_class(DHCPv6OptionCode::VendorClass, b"HTTPClient"));
+        assert!(!packet.has_class(DHCPv6OptionCode::VendorClass, b"PXEClient"));
+    }
+}
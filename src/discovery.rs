@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// What the discovery image reports about a machine's hardware.  Fields
+/// it does not know go in other.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HardwareFacts {
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub cpus: Option<u32>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub disks: Vec<DiskFacts>,
+    #[serde(default)]
+    pub nics: Vec<NicFacts>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DiskFacts {
+    pub name: String,
+    #[serde(default)]
+    pub size_bytes: Option<u64>,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NicFacts {
+    pub name: String,
+    #[serde(default)]
+    pub mac: Option<String>,
+}
+
+/// An unknown machine that has reported in and waits for an operator to
+/// approve it into a host entry
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PendingMachine {
+    pub facts: HardwareFacts,
+    /// Seconds since the Unix epoch
+    pub first_reported: u64,
+    pub last_reported: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_facts() {
+        let facts: HardwareFacts = serde_json::from_str(
+            "{\"serial\": \"ABC123\", \"cpus\": 8, \"memory_mb\": 16384, \
+              \"disks\": [{\"name\": \"sda\", \"size_bytes\": 500107862016}], \
+              \"nics\": [{\"name\": \"eno1\", \"mac\": \"52:54:00:94:9e:f2\"}], \
+              \"bios_vendor\": \"SeaBIOS\"}").unwrap();
+        assert_eq!(Some("ABC123".to_string()), facts.serial);
+        assert_eq!(Some(8), facts.cpus);
+        assert_eq!("sda", facts.disks[0].name);
+        assert_eq!(Some("52:54:00:94:9e:f2".to_string()), facts.nics[0].mac);
+        assert_eq!(Some(&serde_json::json!("SeaBIOS")), facts.other.get("bios_vendor"));
+    }
+}
@@ -0,0 +1,777 @@
+use std::io::{Cursor, Error, Read};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use mac_address::MacAddress;
+use tiny_http::{Header, Response, Server};
+
+use crate::clients::ClientInventory;
+use crate::config::HostConfig;
+use crate::config::ServerConfig;
+use crate::dhcp::MachineConfig;
+use crate::discovery::HardwareFacts;
+use crate::ipxe;
+use crate::provision;
+use crate::provider;
+use crate::provider::HostKey;
+use crate::provider::HostProvider;
+use crate::state::BootState;
+use crate::state::MachineState;
+use crate::state::Report;
+use crate::state::StateStore;
+use crate::token;
+use crate::token::TokenSigner;
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    fn ok(content_type: &'static str, body: String) -> HttpResponse {
+        HttpResponse { status: 200, content_type, body }
+    }
+
+    fn error(status: u16, body: &str) -> HttpResponse {
+        HttpResponse { status, content_type: "text/plain", body: body.to_string() }
+    }
+
+    fn into_response(self) -> Response<Cursor<Vec<u8>>> {
+        let header = Header::from_bytes(&b"Content-Type"[..],
+                                        self.content_type.as_bytes()).unwrap();
+        Response::from_string(self.body)
+            .with_status_code(self.status)
+            .with_header(header)
+    }
+}
+
+// Limits on what an installer can store with a report
+const MAX_STAGE_LEN: usize = 32;
+const MAX_MESSAGE_LEN: usize = 1024;
+// The largest request body read, enough for any host entry or set of
+// hardware facts
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+fn read_body(reader: impl Read) -> Result<String, HttpResponse> {
+    let mut body = Vec::new();
+    if let Err(e) = reader.take(MAX_BODY_LEN as u64 + 1).read_to_end(&mut body) {
+        println!("cannot read request body: {}", e);
+        return Err(HttpResponse::error(400, "cannot read body"));
+    }
+    if body.len() > MAX_BODY_LEN {
+        return Err(HttpResponse::error(413, "body too large"));
+    }
+    String::from_utf8(body).map_err(|_| HttpResponse::error(400, "body is not UTF-8"))
+}
+
+/// The value of a query parameter.  Tokens and the like are expected to
+/// be URL safe, so nothing is decoded.
+fn query_param(url: &str, name: &str) -> Option<String> {
+    let query = url.split_once('?')?.1;
+    query.split('&').find_map(|pair| match pair.split_once('=') {
+        Some((key, value)) if key == name => Some(value.to_string()),
+        _ => None
+    })
+}
+
+/// Compares in time independent of where the tokens differ
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() &&
+        expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn state_json(config: &ServerConfig, machine: &MachineState, host: Option<&HostConfig>) -> String {
+    let configured = config.configured_state(host);
+    serde_json::json!({
+        "state": machine.state,
+        "once": machine.once,
+        "configured": configured,
+        "effective": machine.effective(configured),
+        "reports": machine.reports,
+        "pending": machine.pending,
+    }).to_string()
+}
+
+/// Serves the files that network booted machines fetch once they are
+/// past DHCP.
+pub struct HttpServer {
+    config: ServerConfig,
+    provider: Arc<dyn HostProvider>,
+    states: Arc<StateStore>,
+    tokens: Arc<TokenSigner>,
+    clients: Arc<ClientInventory>,
+    logging: bool,
+}
+
+impl HttpServer {
+    pub fn new(config: ServerConfig, states: Arc<StateStore>, tokens: Arc<TokenSigner>,
+               clients: Arc<ClientInventory>, logging: bool) -> Result<HttpServer, Error> {
+        Ok(HttpServer {
+            provider: provider::from_config(&config, states.clone())?,
+            config,
+            states,
+            tokens,
+            clients,
+            logging
+        })
+    }
+
+    /// Answers a request.  Besides the iPXE scripts there is a management
+    /// API for boot states: GET, PUT or DELETE /api/state/<mac> for the
+    /// recorded state, and PUT or DELETE /api/state/<mac>/once for the
+    /// state of the next boot only.  PUT takes the state name as body.
+    /// Installer configs are at /provision/<mac>/<file>, one-time secrets
+    /// at /secret/<mac>/<name>, and installers report to
+    /// /callback/<mac>/<stage>?token=<token>.  DELETE /api/secrets/<mac>
+    /// lets the secrets of a machine be fetched again.  The discovery image
+    /// posts the facts of an unknown machine to /discovery/<mac>; GET
+    /// /api/pending lists those machines, POST /api/pending/<mac>/approve
+    /// makes one a host, with the host entry as body, and DELETE
+    /// /api/pending/<mac> rejects one.  GET /api/clients lists the clients
+    /// that have asked for an address, those of one relay agent with
+    /// ?relay=<ip>.  Everything under /api/ needs the admin token, sent
+    /// as a bearer token in the Authorization header.
+    pub fn respond(&self, remote: Option<IpAddr>, authorization: Option<&str>, method: &str,
+                   url: &str, body: &str) -> HttpResponse {
+        let path = url.split('?').next().unwrap_or("");
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if parts[0] == "api" {
+            if let Err(response) = self.check_admin(authorization) {
+                return response;
+            }
+        }
+        match (method, parts.as_slice()) {
+            ("GET", ["ipxe", mac]) => self.ipxe_script(remote, url, mac),
+            (_, ["api", "state", mac]) => self.boot_state(method, mac, false, body),
+            (_, ["api", "state", mac, "once"]) => self.boot_state(method, mac, true, body),
+            ("DELETE", ["api", "secrets", mac]) => self.rearm_secrets(mac),
+            ("GET", ["provision", mac, file]) => self.installer_config(remote, url, mac, file),
+            ("GET", ["secret", mac, name]) => self.secret(remote, url, mac, name),
+            ("GET", ["callback", mac, stage]) | ("POST", ["callback", mac, stage]) =>
+                self.callback(mac, stage, query_param(url, "token"), body),
+            ("POST", ["discovery", mac]) => self.discovery(remote, url, mac, body),
+            ("GET", ["api", "pending"]) => self.pending(),
+            ("POST", ["api", "pending", mac, "approve"]) => self.approve(mac, Some(body)),
+            ("DELETE", ["api", "pending", mac]) => self.approve(mac, None),
+            ("GET", ["api", "clients"]) => self.clients(url),
+            ("GET", _) => HttpResponse::error(404, "not found"),
+            _ => HttpResponse::error(405, "method not allowed"),
+        }
+    }
+
+    fn lookup_host(&self, mac: MacAddress) -> Result<Option<HostConfig>, HttpResponse> {
+        self.provider.lookup(&HostKey { mac: Some(mac), ..HostKey::default() }).map_err(|e| {
+            println!("host lookup for {} failed: {}", mac, e);
+            HttpResponse::error(503, "host lookup failed")
+        })
+    }
+
+    /// Without an admin token in the config the management API is off
+    fn check_admin(&self, authorization: Option<&str>) -> Result<(), HttpResponse> {
+        let expected = match self.config.admin_token.as_deref() {
+            Some(expected) if !expected.is_empty() => expected,
+            _ => return Err(HttpResponse::error(403, "management API is disabled"))
+        };
+        match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(token) if tokens_match(expected, token.trim()) => Ok(()),
+            _ => Err(HttpResponse::error(401, "admin token required"))
+        }
+    }
+
+    /// When tokens are enabled, a request for a machine's files has to
+    /// carry a token for it.
+    fn check_token(&self, remote: Option<IpAddr>, url: &str,
+                   mac: &MacAddress) -> Result<(), HttpResponse> {
+        if !self.config.tokens.enabled {
+            return Ok(());
+        }
+        let token = query_param(url, "token").
+            ok_or_else(|| HttpResponse::error(403, "token required"))?;
+        self.tokens.verify(&token, mac, remote, token::now()).map_err(|e| {
+            println!("refused request for {} from {:?}: {}", mac, remote, e);
+            HttpResponse::error(403, e)
+        })
+    }
+
+    /// A fresh token for the URLs in a script or installer config
+    fn url_token(&self, remote: Option<IpAddr>, mac: &MacAddress) -> Option<String> {
+        if !self.config.tokens.enabled {
+            return None;
+        }
+        let ip = match remote {
+            Some(IpAddr::V4(ip)) if self.config.tokens.bind_ip => Some(ip),
+            _ => None
+        };
+        Some(self.tokens.sign(mac, ip, token::now() + self.config.tokens.lifetime_secs))
+    }
+
+    fn boot_state(&self, method: &str, mac: &str, once: bool, body: &str) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        let state = match method {
+            "GET" if !once => None,
+            "PUT" => match BootState::from_str(body) {
+                Ok(state) => Some(state),
+                Err(e) => return HttpResponse::error(400, e)
+            },
+            "DELETE" => None,
+            _ => return HttpResponse::error(405, "method not allowed"),
+        };
+        let saved = match (method, once) {
+            ("GET", _) => Ok(self.states.get(&mac)),
+            (_, true) => self.states.set_once(&mac, state),
+            (_, false) => self.states.set(&mac, state),
+        };
+        let machine = match saved {
+            Ok(machine) => machine,
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                return HttpResponse::error(500, "cannot save boot state");
+            }
+        };
+        let host = match self.lookup_host(mac) {
+            Ok(host) => host,
+            Err(response) => return response
+        };
+        HttpResponse::ok("application/json", state_json(&self.config, &machine, host.as_ref()))
+    }
+
+    /// A report from an installer.  It has to carry the host's token, and
+    /// can move the machine to the state configured for its stage.
+    fn callback(&self, mac: &str, stage: &str, token: Option<String>, body: &str) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        if stage.is_empty() || stage.len() > MAX_STAGE_LEN ||
+            !stage.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return HttpResponse::error(400, "bad stage");
+        }
+        let host = match self.lookup_host(mac) {
+            Ok(host) => host,
+            Err(response) => return response
+        };
+        let expected = host.as_ref().and_then(|host| host.token.as_deref());
+        match (expected, token) {
+            (Some(expected), Some(token)) if tokens_match(expected, &token) => {},
+            _ => return HttpResponse::error(403, "bad token")
+        }
+        let report = Report {
+            stage: stage.to_string(),
+            time: token::now(),
+            message: body.chars().take(MAX_MESSAGE_LEN).collect(),
+        };
+        let next = self.config.stage_states.get(stage).copied();
+        match self.states.report(&mac, report, next) {
+            Ok(machine) => {
+                println!("{} reported {}{}", mac, stage,
+                         next.map(|state| format!(", now {}", state)).unwrap_or_default());
+                let json = state_json(&self.config, &machine, host.as_ref());
+                HttpResponse::ok("application/json", json)
+            },
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                HttpResponse::error(500, "cannot save boot state")
+            }
+        }
+    }
+
+    /// A kickstart, preseed or cloud-init file.  cloud-init NoCloud is
+    /// pointed at /provision/<mac>/ and fetches meta-data and user-data.
+    fn installer_config(&self, remote: Option<IpAddr>, url: &str, mac: &str,
+                        file: &str) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        if let Err(response) = self.check_token(remote, url, &mac) {
+            return response;
+        }
+        let host = match self.lookup_host(mac) {
+            Ok(host) => host,
+            Err(response) => return response
+        };
+        let state = self.states.get(&mac).effective(self.config.configured_state(host.as_ref()));
+        let machine = MachineConfig::for_machine(&self.config, mac, host, state);
+        let url_token = self.url_token(remote, &mac);
+        match provision::render(&self.config, &machine, file, url_token.as_deref()) {
+            Ok(Some(text)) => HttpResponse::ok("text/plain", text),
+            Ok(None) => HttpResponse::error(404, "not found"),
+            Err(e) => {
+                println!("cannot render {} for {}: {}", file, mac, e);
+                HttpResponse::error(500, "cannot render installer config")
+            }
+        }
+    }
+
+    /// A one-time secret of the machine.  Once given out it is gone until
+    /// it is rearmed through the API.
+    fn secret(&self, remote: Option<IpAddr>, url: &str, mac: &str, name: &str) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        if let Err(response) = self.check_token(remote, url, &mac) {
+            return response;
+        }
+        let host = match self.lookup_host(mac) {
+            Ok(host) => host,
+            Err(response) => return response
+        };
+        let secret = match host.as_ref().and_then(|host| host.secrets.get(name)) {
+            Some(secret) => secret,
+            None => return HttpResponse::error(404, "not found")
+        };
+        match self.states.use_secret(&mac, name) {
+            Ok(true) => {
+                println!("gave secret {} to {} at {:?}", name, mac, remote);
+                HttpResponse::ok("text/plain", secret.clone())
+            },
+            Ok(false) => {
+                println!("refused secret {} to {} at {:?}: already given out", name, mac, remote);
+                HttpResponse::error(410, "secret already retrieved")
+            },
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                HttpResponse::error(500, "cannot save boot state")
+            }
+        }
+    }
+
+    fn rearm_secrets(&self, mac: &str) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        let machine = match self.states.rearm_secrets(&mac) {
+            Ok(machine) => machine,
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                return HttpResponse::error(500, "cannot save boot state");
+            }
+        };
+        let host = match self.lookup_host(mac) {
+            Ok(host) => host,
+            Err(response) => return response
+        };
+        HttpResponse::ok("application/json", state_json(&self.config, &machine, host.as_ref()))
+    }
+
+    /// The hardware facts of a machine booted into discovery.  Machines
+    /// that already have a host entry are left alone.
+    fn discovery(&self, remote: Option<IpAddr>, url: &str, mac: &str, body: &str) -> HttpResponse {
+        if !self.config.discovery.enabled {
+            return HttpResponse::error(404, "discovery is not enabled");
+        }
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        if let Err(response) = self.check_token(remote, url, &mac) {
+            return response;
+        }
+        let facts: HardwareFacts = match serde_json::from_str(body) {
+            Ok(facts) => facts,
+            Err(_) => return HttpResponse::error(400, "bad hardware facts")
+        };
+        match self.lookup_host(mac) {
+            Ok(None) => {},
+            Ok(Some(_)) => return HttpResponse::error(409, "machine already has a host entry"),
+            Err(response) => return response
+        }
+        match self.states.report_facts(&mac, facts, token::now()) {
+            Ok(machine) => {
+                println!("discovered {} at {:?}", mac, remote);
+                HttpResponse::ok("application/json", state_json(&self.config, &machine, None))
+            },
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                HttpResponse::error(500, "cannot save boot state")
+            }
+        }
+    }
+
+    fn pending(&self) -> HttpResponse {
+        let pending = serde_json::to_string(&self.states.pending()).unwrap_or_default();
+        HttpResponse::ok("application/json", pending)
+    }
+
+    /// Approves a pending machine with the host entry in the body, or with
+    /// no body, rejects it.  An entry without any identifier is for the
+    /// machine's MAC address.  Secrets and callback tokens only come from
+    /// the config, so an entry carrying them is refused.
+    fn approve(&self, mac: &str, body: Option<&str>) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        let host = match body {
+            Some(body) => {
+                let body = if body.trim().is_empty() { "{}" } else { body };
+                let mut host: HostConfig = match serde_json::from_str(body) {
+                    Ok(host) => host,
+                    Err(_) => return HttpResponse::error(400, "bad host entry")
+                };
+                if !host.secrets.is_empty() || host.token.is_some() {
+                    return HttpResponse::error(400, "secrets and tokens cannot be set here");
+                }
+                if host.mac.is_none() && host.client_id.is_none() && host.duid.is_none() &&
+                    host.uuid.is_none() {
+                    host.mac = Some(mac);
+                }
+                if let Err(e) = host.check() {
+                    return HttpResponse::error(400, e);
+                }
+                if self.config.reserves_pool_address(&host) {
+                    return HttpResponse::error(400, "address is in a dynamic pool");
+                }
+                let known_class = |name: &String|
+                    self.config.classes.iter().any(|class| class.name == *name);
+                if !host.classes.iter().all(known_class) {
+                    return HttpResponse::error(400, "host is in an unknown class");
+                }
+                Some(host)
+            },
+            None => None
+        };
+        let approved = host.is_some();
+        match self.states.approve(&mac, host) {
+            Ok(Some(machine)) => {
+                println!("{} {}", if approved { "approved" } else { "rejected" }, mac);
+                let host = machine.host.as_ref();
+                HttpResponse::ok("application/json", state_json(&self.config, &machine, host))
+            },
+            Ok(None) => HttpResponse::error(404, "machine is not pending"),
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                HttpResponse::error(500, "cannot save boot state")
+            }
+        }
+    }
+
+    fn clients(&self, url: &str) -> HttpResponse {
+        let relay = match query_param(url, "relay").map(|relay| relay.parse()) {
+            Some(Ok(relay)) => Some(relay),
+            Some(Err(_)) => return HttpResponse::error(400, "bad relay address"),
+            None => None
+        };
+        let clients = serde_json::to_string(&self.clients.clients(relay)).unwrap_or_default();
+        HttpResponse::ok("application/json", clients)
+    }
+
+    fn ipxe_script(&self, remote: Option<IpAddr>, url: &str, mac: &str) -> HttpResponse {
+        let mac = match MacAddress::from_str(mac) {
+            Ok(mac) => mac,
+            Err(_) => return HttpResponse::error(400, "bad MAC address")
+        };
+        if let Err(response) = self.check_token(remote, url, &mac) {
+            return response;
+        }
+        let host = match self.lookup_host(mac) {
+            Ok(host) => host,
+            Err(response) => return response
+        };
+        let state = match self.states.boot(&mac, self.config.configured_state(host.as_ref())) {
+            Ok(state) => state,
+            Err(e) => {
+                println!("cannot save boot state of {}: {}", mac, e);
+                return HttpResponse::error(500, "cannot save boot state");
+            }
+        };
+        let url_token = self.url_token(remote, &mac);
+        match ipxe::render_script(&self.config, &mac, host.as_ref(), state, url_token.as_deref()) {
+            Ok(script) => HttpResponse::ok("text/plain", script),
+            Err(e) => {
+                println!("cannot render iPXE script for {}: {}", mac, e);
+                HttpResponse::error(500, "cannot render script")
+            }
+        }
+    }
+
+    pub fn run(&self) -> std::io::Result<()> {
+        let server = Server::http(self.config.ipxe.listen).map_err(Error::other)?;
+        for mut request in server.incoming_requests() {
+            let body = read_body(request.as_reader());
+            let response = match body {
+                Ok(body) => {
+                    let remote = request.remote_addr().map(|addr| addr.ip());
+                    let authorization = request.headers().iter().
+                        find(|header| header.field.equiv("Authorization")).
+                        map(|header| header.value.to_string());
+                    self.respond(remote, authorization.as_deref(), request.method().as_str(),
+                                 request.url(), &body)
+                },
+                Err(response) => response
+            };
+            if self.logging {
+                println!("http {} {} -> {}", request.method(), request.url(),
+                         response.status);
+            }
+            if let Err(e) = request.respond(response.into_response()) {
+                println!("cannot send response: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: Option<&str> = Some("Bearer adm1n");
+
+    fn server(config: ServerConfig) -> HttpServer {
+        HttpServer::new(config, Arc::default(), Arc::default(), Arc::default(), false).unwrap()
+    }
+
+    #[test]
+    fn test_ipxe_script() {
+        let server = server(ServerConfig::default());
+        let response = server.respond(None, None, "GET", "/ipxe/52:54:00:94:9e:f2", "");
+        assert_eq!(200, response.status);
+        assert!(response.body.starts_with("#!ipxe\n"));
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let server = server(ServerConfig::default());
+        assert_eq!(400, server.respond(None, None, "GET", "/ipxe/not-a-mac", "").status);
+        assert_eq!(404, server.respond(None, None, "GET", "/nothing/here", "").status);
+        assert_eq!(405, server.respond(None, None, "PUT", "/ipxe/52:54:00:94:9e:f2", "").status);
+    }
+
+    #[test]
+    fn test_state_api() {
+        let config = ServerConfig::parse(
+            "admin_token = \"adm1n\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             state = \"install\"\n\
+             kernel = \"http://10.0.0.2/installer\"\n").unwrap();
+        let disabled = server(ServerConfig::default());
+        let server = server(config);
+        let url = "/api/state/52:54:00:94:9e:f2";
+        assert_eq!(401, server.respond(None, None, "GET", url, "").status);
+        assert_eq!(401, server.respond(None, Some("Bearer wrong"), "PUT", url, "rescue").status);
+        assert_eq!(401, server.respond(None, Some("adm1n"), "GET", url, "").status);
+        assert_eq!(403, disabled.respond(None, ADMIN, "GET", url, "").status);
+        let response = server.respond(None, ADMIN, "GET", url, "");
+        assert_eq!(200, response.status);
+        assert!(response.body.contains("\"effective\":\"install\""));
+
+        assert_eq!(200, server.respond(None, ADMIN, "PUT", url, "installed").status);
+        let effective = |server: &HttpServer| server.respond(None, ADMIN, "GET", url, "").body;
+        let once = format!("{}/once", url);
+        assert_eq!(200, server.respond(None, ADMIN, "PUT", &once, "rescue").status);
+        assert!(effective(&server).contains("\"effective\":\"rescue\""));
+
+        // The one-shot state is used by one boot, then the machine boots
+        // its local disk.
+        server.respond(None, None, "GET", "/ipxe/52:54:00:94:9e:f2", "");
+        assert!(effective(&server).contains("\"effective\":\"localboot\""));
+        let script = server.respond(None, None, "GET", "/ipxe/52:54:00:94:9e:f2", "").body;
+        assert!(!script.contains("installer"));
+
+        assert_eq!(200, server.respond(None, ADMIN, "DELETE", url, "").status);
+        assert!(effective(&server).contains("\"effective\":\"install\""));
+        assert_eq!(400, server.respond(None, ADMIN, "PUT", url, "reinstall").status);
+        assert_eq!(405, server.respond(None, ADMIN, "GET", &once, "").status);
+    }
+
+    #[test]
+    fn test_callback() {
+        let config = ServerConfig::parse(
+            "admin_token = \"adm1n\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             state = \"install\"\n\
+             token = \"s3cret\"\n").unwrap();
+        let server = server(config);
+        let url = "/callback/52:54:00:94:9e:f2";
+        assert_eq!(403, server.respond(None, None, "POST", &format!("{}/started", url), "").status);
+        let wrong = format!("{}/started?token=wrong", url);
+        assert_eq!(403, server.respond(None, None, "POST", &wrong, "").status);
+        let other = "/callback/52:54:00:00:00:01/done?token=s3cret";
+        assert_eq!(403, server.respond(None, None, "POST", other, "").status);
+        let bad_stage = format!("{}/a.b?token=s3cret", url);
+        assert_eq!(400, server.respond(None, None, "POST", &bad_stage, "").status);
+
+        let response = server.respond(None, None, "POST", &format!("{}/started?token=s3cret", url),
+                                      "partitioning");
+        assert_eq!(200, response.status);
+        assert!(response.body.contains("\"effective\":\"install\""));
+        assert!(response.body.contains("\"message\":\"partitioning\""));
+
+        let response = server.respond(None, None, "GET", &format!("{}/done?token=s3cret", url), "");
+        assert!(response.body.contains("\"effective\":\"localboot\""));
+        let state = server.respond(None, ADMIN, "GET", "/api/state/52:54:00:94:9e:f2", "").body;
+        assert!(state.contains("\"stage\":\"started\""));
+        assert!(state.contains("\"stage\":\"done\""));
+    }
+
+    #[test]
+    fn test_discovery_disabled() {
+        let config = ServerConfig::parse("admin_token = \"adm1n\"\n").unwrap();
+        let server = server(config);
+        let url = "/discovery/52:54:00:94:9e:f2";
+        assert_eq!(404, server.respond(None, None, "POST", url, "{\"cpus\": 4}").status);
+        assert_eq!("{}", server.respond(None, ADMIN, "GET", "/api/pending", "").body);
+    }
+
+    #[test]
+    fn test_discovery_and_approval() {
+        let config = ServerConfig::parse(
+            "admin_token = \"adm1n\"\n\
+             [discovery]\n\
+             enabled = true\n\
+             kernel = \"http://10.0.0.2/discover\"\n\
+             [[classes]]\n\
+             name = \"web\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:00:00:01\"\n").unwrap();
+        let server = server(config);
+        let script = server.respond(None, None, "GET", "/ipxe/52:54:00:94:9e:f2", "").body;
+        assert!(script.contains("http://10.0.0.2/discover"), "{}", script);
+
+        let url = "/discovery/52:54:00:94:9e:f2";
+        assert_eq!(400, server.respond(None, None, "POST", url, "not json").status);
+        let known = "/discovery/52:54:00:00:00:01";
+        assert_eq!(409, server.respond(None, None, "POST", known, "{}").status);
+        let facts = "{\"serial\": \"ABC123\", \"cpus\": 4}";
+        let response = server.respond(None, None, "POST", url, facts);
+        assert_eq!(200, response.status);
+        assert!(response.body.contains("\"effective\":\"discover\""));
+        let pending = server.respond(None, ADMIN, "GET", "/api/pending", "").body;
+        assert!(pending.contains("\"52:54:00:94:9e:f2\""), "{}", pending);
+        assert!(pending.contains("\"serial\":\"ABC123\""), "{}", pending);
+
+        let approve = "/api/pending/52:54:00:94:9e:f2/approve";
+        let unknown_class = "{\"classes\": [\"db\"]}";
+        assert_eq!(400, server.respond(None, ADMIN, "POST", approve, unknown_class).status);
+        assert_eq!(401, server.respond(None, None, "POST", approve, "{}").status);
+        assert_eq!(400, server.respond(None, ADMIN, "POST", approve, "{\"token\": \"x\"}").status);
+        let pool_address = "{\"ip\": \"192.168.144.150\"}";
+        assert_eq!(400, server.respond(None, ADMIN, "POST", approve, pool_address).status);
+        assert_eq!(400, server.respond(None, ADMIN, "POST", approve,
+                                       "{\"secrets\": {\"root\": \"$6$x\"}}").status);
+        let response = server.respond(None, ADMIN, "POST", approve,
+                                      "{\"hostname\": \"web7\", \"state\": \"install\", \
+                                        \"classes\": [\"web\"]}");
+        assert_eq!(200, response.status);
+        assert!(response.body.contains("\"effective\":\"install\""));
+        assert_eq!("{}", server.respond(None, ADMIN, "GET", "/api/pending", "").body);
+        assert_eq!(404, server.respond(None, ADMIN, "POST", approve, "").status);
+        assert_eq!(409, server.respond(None, None, "POST", url, "{}").status);
+        let script = server.respond(None, None, "GET", "/ipxe/52:54:00:94:9e:f2", "").body;
+        assert!(!script.contains("http://10.0.0.2/discover"), "{}", script);
+
+        server.respond(None, None, "POST", "/discovery/52:54:00:00:00:02", "{}");
+        let reject = "/api/pending/52:54:00:00:00:02";
+        assert_eq!(200, server.respond(None, ADMIN, "DELETE", reject, "").status);
+        assert_eq!("{}", server.respond(None, ADMIN, "GET", "/api/pending", "").body);
+    }
+
+    #[test]
+    fn test_clients() {
+        let clients = Arc::new(ClientInventory::default());
+        let relayed = crate::clients::ClientDetails {
+            relay: Some("10.0.1.1".parse().unwrap()),
+            ..Default::default()
+        };
+        clients.observe("01:52:54:00:94:9e:f2", relayed, "discover", 10);
+        clients.observe("01:52:54:00:00:00:01", Default::default(), "discover", 10);
+        let config = ServerConfig {
+            admin_token: Some("adm1n".to_string()),
+            ..ServerConfig::default()
+        };
+        let server = HttpServer::new(config, Arc::default(), Arc::default(), clients,
+                                     false).unwrap();
+        let all = server.respond(None, ADMIN, "GET", "/api/clients", "").body;
+        assert!(all.contains("01:52:54:00:00:00:01"), "{}", all);
+        let response = server.respond(None, ADMIN, "GET", "/api/clients?relay=10.0.1.1", "");
+        assert_eq!(200, response.status);
+        assert!(response.body.contains("\"relay\":\"10.0.1.1\""), "{}", response.body);
+        assert!(!response.body.contains("01:52:54:00:00:00:01"), "{}", response.body);
+        assert_eq!(400, server.respond(None, ADMIN, "GET", "/api/clients?relay=vlan7", "").status);
+    }
+
+    #[test]
+    fn test_read_body() {
+        assert_eq!("{}", read_body(Cursor::new(b"{}".to_vec())).ok().unwrap());
+        let largest = vec![b'x'; MAX_BODY_LEN];
+        assert_eq!(MAX_BODY_LEN, read_body(Cursor::new(largest)).ok().unwrap().len());
+        let too_large = vec![b'x'; MAX_BODY_LEN + 1];
+        assert_eq!(413, read_body(Cursor::new(too_large)).err().unwrap().status);
+        assert_eq!(400, read_body(Cursor::new(vec![0xff, 0xfe])).err().unwrap().status);
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(Some("abc".to_string()), query_param("/x?a=1&token=abc", "token"));
+        assert_eq!(None, query_param("/x?tokens=abc", "token"));
+        assert_eq!(None, query_param("/x", "token"));
+    }
+
+    #[test]
+    fn test_installer_config() {
+        let server = server(ServerConfig::default());
+        let get = |url| server.respond(None, None, "GET", url, "");
+        let response = get("/provision/52:54:00:94:9e:f2/meta-data");
+        assert_eq!(200, response.status);
+        assert_eq!("instance-id: 52:54:00:94:9e:f2\n", response.body);
+        assert_eq!(404, get("/provision/52:54:00:94:9e:f2/kickstart").status);
+        assert_eq!(404, get("/provision/52:54:00:94:9e:f2/..").status);
+    }
+
+    #[test]
+    fn test_tokens_and_one_time_secrets() {
+        let config = ServerConfig::parse(
+            "admin_token = \"adm1n\"\n\
+             [tokens]\n\
+             enabled = true\n\
+             bind_ip = true\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             secrets = { root = \"$6$hash\" }\n").unwrap();
+        let tokens = Arc::new(TokenSigner::generate().unwrap());
+        let server = HttpServer::new(config, Arc::default(), tokens.clone(), Arc::default(),
+                                     false).unwrap();
+        let mac = MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2]);
+        let installer: IpAddr = "10.0.0.5".parse().unwrap();
+        let token = tokens.sign(&mac, None, token::now() + 60);
+        let secret = format!("/secret/52:54:00:94:9e:f2/root?token={}", token);
+
+        assert_eq!(403, server.respond(None, None, "GET", "/ipxe/52:54:00:94:9e:f2", "").status);
+        let unsigned = "/secret/52:54:00:94:9e:f2/root";
+        assert_eq!(403, server.respond(None, None, "GET", unsigned, "").status);
+        let other = format!("/secret/52:54:00:00:00:01/root?token={}", token);
+        assert_eq!(403, server.respond(None, None, "GET", &other, "").status);
+
+        // The script the machine gets carries a new token bound to its address
+        let script = format!("/ipxe/52:54:00:94:9e:f2?token={}", token);
+        assert_eq!(200, server.respond(Some(installer), None, "GET", &script, "").status);
+        let bound = tokens.sign(&mac, Some("10.0.0.5".parse().unwrap()), token::now() + 60);
+        let bound_secret = format!("/secret/52:54:00:94:9e:f2/root?token={}", bound);
+        assert_eq!(403, server.respond(None, None, "GET", &bound_secret, "").status);
+
+        let response = server.respond(Some(installer), None, "GET", &bound_secret, "");
+        assert_eq!(200, response.status);
+        assert_eq!("$6$hash", response.body);
+        assert_eq!(410, server.respond(Some(installer), None, "GET", &secret, "").status);
+        // Only an operator can let the secrets be fetched again
+        let rearm = "/api/secrets/52:54:00:94:9e:f2";
+        assert_eq!(401, server.respond(Some(installer), None, "DELETE", rearm, "").status);
+        let query_token = format!("{}?token={}", rearm, bound);
+        assert_eq!(401, server.respond(Some(installer), None, "DELETE", &query_token, "").status);
+        assert_eq!(410, server.respond(Some(installer), None, "GET", &bound_secret, "").status);
+        assert_eq!(200, server.respond(None, ADMIN, "DELETE", rearm, "").status);
+        assert_eq!(200, server.respond(None, None, "GET", &secret, "").status);
+        let unknown = secret.replace("root", "key");
+        assert_eq!(404, server.respond(None, None, "GET", &unknown, "").status);
+    }
+}
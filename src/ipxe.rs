@@ -0,0 +1,124 @@
+use std::fs;
+
+use mac_address::MacAddress;
+use minijinja::{context, Environment};
+
+use crate::config::{mac_path, HostConfig, ServerConfig};
+use crate::state::BootState;
+
+const DEFAULT_SCRIPT: &str = include_str!("../templates/boot.ipxe");
+const DISCOVER_SCRIPT: &str = include_str!("../templates/discover.ipxe");
+
+/// Renders the iPXE script for a machine and its host entry, if it has
+/// one.  The template is read on every request, so a change to it takes
+/// effect on the next boot.
+pub fn render_script(config: &ServerConfig, mac: &MacAddress, host: Option<&HostConfig>,
+                     state: Option<BootState>, url_token: Option<&str>) -> Result<String, String> {
+    let template = state.and_then(|state| config.states.profile(state).template.as_ref());
+    let source = match template {
+        Some(filename) => fs::read_to_string(filename).map_err(
+            |e| format!("cannot read template {}: {}", filename, e))?,
+        None if state == Some(BootState::Discover) => DISCOVER_SCRIPT.to_string(),
+        None => match &config.ipxe.template {
+            Some(filename) => fs::read_to_string(filename).map_err(
+                |e| format!("cannot read template {}: {}", filename, e))?,
+            None => DEFAULT_SCRIPT.to_string()
+        }
+    };
+    let facts_url = config.ipxe.facts_url(mac);
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    env.add_template("boot.ipxe", &source).map_err(|e| e.to_string())?;
+    let template = env.get_template("boot.ipxe").map_err(|e| e.to_string())?;
+    template.render(context!{
+        mac => mac_path(mac),
+        server_ip => config.server_ip.to_string(),
+        host => host,
+        state => state.map(|state| state.name()),
+        callback_url => config.ipxe.callback_url(mac),
+        token => host.and_then(|host| host.token.as_deref()),
+        url_token => url_token,
+        discovery => &config.discovery,
+        facts_url => match url_token {
+            Some(token) => format!("{}?token={}", facts_url, token),
+            None => facts_url
+        },
+    }).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{find_host, HostKey};
+
+    fn test_mac() -> MacAddress {
+        MacAddress::new([0x52, 0x54, 0x00, 0x94, 0x9e, 0xf2])
+    }
+
+    fn test_host(config: &ServerConfig) -> Option<&HostConfig> {
+        find_host(&config.hosts, &HostKey { mac: Some(test_mac()), ..HostKey::default() })
+    }
+
+    #[test]
+    fn test_render_known_host() {
+        let config = ServerConfig::parse(
+            "[[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             kernel = \"http://10.0.0.2/vmlinuz\"\n\
+             initrd = \"http://10.0.0.2/initrd.img\"\n\
+             kernel_args = \"console=ttyS0\"\n\
+             fallback = [\"http://10.0.0.2/rescue.ipxe\"]\n").unwrap();
+        let script = render_script(&config, &test_mac(), test_host(&config), None, None).unwrap();
+        assert_eq!("#!ipxe\n\
+                    # Generated by rustboot for 52:54:00:94:9e:f2\n\
+                    kernel http://10.0.0.2/vmlinuz console=ttyS0\n\
+                    initrd http://10.0.0.2/initrd.img\n\
+                    boot ||\n\
+                    chain http://10.0.0.2/rescue.ipxe ||\n\
+                    exit\n", script);
+    }
+
+    #[test]
+    fn test_render_unknown_host_exits() {
+        let script = render_script(&ServerConfig::default(), &test_mac(), None, None, None).unwrap();
+        assert_eq!("#!ipxe\n\
+                    # Generated by rustboot for 52:54:00:94:9e:f2\n\
+                    exit\n", script);
+    }
+
+    #[test]
+    fn test_render_by_state() {
+        let config = ServerConfig::parse(
+            "[[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             kernel = \"http://10.0.0.2/vmlinuz\"\n").unwrap();
+        let host = test_host(&config);
+        let script = render_script(&config, &test_mac(), host, Some(BootState::Localboot), None).unwrap();
+        assert_eq!("#!ipxe\n\
+                    # Generated by rustboot for 52:54:00:94:9e:f2\n\
+                    exit\n", script);
+        let script = render_script(&config, &test_mac(), host, Some(BootState::Install), None).unwrap();
+        assert!(script.contains("kernel http://10.0.0.2/vmlinuz"));
+    }
+
+    #[test]
+    fn test_render_discovery() {
+        let config = ServerConfig::parse(
+            "[discovery]\n\
+             enabled = true\n\
+             kernel = \"http://10.0.0.2/discover/vmlinuz\"\n\
+             initrd = \"http://10.0.0.2/discover/initrd.img\"\n\
+             kernel_args = \"quiet\"\n").unwrap();
+        let script = render_script(&config, &test_mac(), None, config.configured_state(None),
+                                   Some("1-2-3")).unwrap();
+        assert_eq!("#!ipxe\n\
+                    # Discovery for 52:54:00:94:9e:f2\n\
+                    kernel http://10.0.0.2/discover/vmlinuz quiet \
+                    rustboot.facts_url=http://192.168.144.1:8080/discovery/52:54:00:94:9e:f2?token=1-2-3\n\
+                    initrd http://10.0.0.2/discover/initrd.img\n\
+                    boot ||\n\
+                    exit\n", script);
+    }
+}
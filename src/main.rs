@@ -1,6 +1,19 @@
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::thread;
 use clap::Clap;
 
+mod clients;
+mod config;
 mod dhcp;
+mod dhcp6;
+mod discovery;
+mod http;
+mod ipxe;
+mod provider;
+mod provision;
+mod state;
+mod token;
 /// run the rustboot server
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Adam Young <adam@younglogic.com>")]
@@ -21,13 +34,59 @@ struct Opts {
     /// If the server should write captured packets to disk
     #[clap(short)]
     write_capture: bool,
+
+    /// Print the inventory of clients seen and exit
+    #[clap(long)]
+    clients: bool,
+
+    /// With --clients, only those whose requests came through this relay agent
+    #[clap(long)]
+    relay: Option<std::net::Ipv4Addr>,
 }
 
 fn main() -> std::io::Result<()> {
 
     let opts: Opts = Opts::parse();
     println!("Value for config: {}", opts.config);
-    let server = dhcp::DHCPServer::new( opts.verbose > 0,
+    let server_config = match config::ServerConfig::from_file(&opts.config) {
+        Ok(server_config) => server_config,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            println!("config file not found, using defaults");
+            config::ServerConfig::default()
+        },
+        Err(e) => return Err(e)
+    };
+    let clients = Arc::new(clients::ClientInventory::open(server_config.clients_file.as_deref())?);
+    if opts.clients {
+        println!("{}", clients::table(&clients.clients(opts.relay)));
+        return Ok(());
+    }
+    let saver = clients.clone();
+    thread::spawn(move || saver.save_periodically());
+    let states = Arc::new(state::StateStore::open(server_config.state_file.as_deref())?);
+    let tokens = Arc::new(token::TokenSigner::from_config(&server_config.tokens)?);
+    let http_server = http::HttpServer::new(server_config.clone(), states.clone(),
+                                            tokens.clone(), clients.clone(),
+                                            opts.verbose > 0)?;
+    thread::spawn(move || {
+        if let Err(e) = http_server.run() {
+            println!("HTTP server stopped: {}", e);
+        }
+    });
+    if server_config.dhcp6.enabled {
+        let dhcp6_server = dhcp6::DHCPv6Server::new(server_config.clone(), states.clone(),
+                                                    tokens.clone(), opts.verbose > 0)?;
+        thread::spawn(move || {
+            if let Err(e) = dhcp6_server.run() {
+                println!("DHCPv6 server stopped: {}", e);
+            }
+        });
+    }
+    let server = dhcp::DHCPServer::new( server_config,
+                                        states,
+                                        tokens,
+                                        clients,
+                                        opts.verbose > 0,
                                         opts.write_capture,
                                         &opts.packet_capture_dir )?;
     server.run()?;
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::format_uuid;
+use crate::config::join_url;
+use crate::config::HostConfig;
+
+use super::HostKey;
+use super::HostProvider;
+
+/// Hosts kept by an inventory service.  It is asked with
+/// GET <url>/hosts?mac=..&client_id=..&duid=..&uuid=.. and answers with a host
+/// as JSON, in the same fields as the config file, or 404 if it does
+/// not know the machine.  Answers are kept for a few seconds, as a
+/// client sends several requests in one boot.
+pub struct InventoryProvider {
+    url: String,
+    agent: ureq::Agent,
+    cache: Mutex<HashMap<HostKey, (Instant, Option<HostConfig>)>>,
+}
+
+// How long an answer from the inventory is used for
+const CACHE_SECS: u64 = 10;
+
+impl InventoryProvider {
+    pub fn new(url: &str, timeout_secs: u64) -> InventoryProvider {
+        InventoryProvider {
+            url: join_url(url, "hosts"),
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_secs(timeout_secs)).build(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn fetch(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        let mut request = self.agent.get(&self.url);
+        if let Some(mac) = key.mac {
+            request = request.query("mac", &mac.to_string().to_lowercase());
+        }
+        let hex = |bytes: &[u8]| -> String {
+            bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+        };
+        if let Some(client_id) = &key.client_id {
+            request = request.query("client_id", &hex(client_id));
+        }
+        if let Some(duid) = &key.duid {
+            request = request.query("duid", &hex(duid));
+        }
+        for uuid in &key.uuids {
+            request = request.query("uuid", &format_uuid(uuid));
+        }
+        let host: HostConfig = match request.call() {
+            Ok(response) => response.into_json().
+                map_err(|e| format!("{}: bad host: {}", self.url, e))?,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", self.url, e)),
+        };
+        host.check().map_err(|e| format!("{}: {}", self.url, e))?;
+        Ok(Some(host))
+    }
+}
+
+impl HostProvider for InventoryProvider {
+    /// Failures are not kept, so the next request asks again
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        let ttl = Duration::from_secs(CACHE_SECS);
+        if let Some((fetched, host)) = self.cache.lock().unwrap().get(key) {
+            if fetched.elapsed() < ttl {
+                return Ok(host.clone());
+            }
+        }
+        let host = self.fetch(key)?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < ttl);
+        cache.insert(key.clone(), (Instant::now(), host.clone()));
+        Ok(host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use mac_address::MacAddress;
+    use tiny_http::{Response, Server};
+
+    // Answers the given number of requests: one host for a known MAC,
+    // 404 for any other, and 500 when no MAC is given.
+    fn stub_inventory(requests: usize) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api", server.server_addr().to_ip().unwrap());
+        thread::spawn(move || {
+            for request in server.incoming_requests().take(requests) {
+                let response = if request.url() == "/api/hosts?mac=52%3A54%3A00%3A94%3A9e%3Af2" {
+                    Response::from_string("{\"mac\": \"52:54:00:94:9e:f2\", \
+                                           \"hostname\": \"inventoried\"}")
+                } else if request.url().contains("mac=") {
+                    Response::from_string("").with_status_code(404)
+                } else {
+                    Response::from_string("").with_status_code(500)
+                };
+                request.respond(response).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_lookup_against_stub() {
+        let provider = InventoryProvider::new(&stub_inventory(3), 2);
+        let mut key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        let host = provider.lookup(&key).unwrap().unwrap();
+        assert_eq!(Some("inventoried".to_string()), host.hostname);
+
+        key.mac = Some(MacAddress::new([0x52, 0x54, 0, 0, 0, 1]));
+        assert!(provider.lookup(&key).unwrap().is_none());
+        assert!(provider.lookup(&HostKey::default()).is_err());
+    }
+
+    #[test]
+    fn test_answers_cached() {
+        let provider = InventoryProvider::new(&stub_inventory(2), 2);
+        let key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        let unknown = HostKey { mac: Some(MacAddress::new([0x52, 0x54, 0, 0, 0, 1])), ..key.clone() };
+        // The stub is gone after two requests, so these come from the cache
+        for _ in 0..3 {
+            assert_eq!(Some("inventoried".to_string()),
+                       provider.lookup(&key).unwrap().and_then(|host| host.hostname));
+            assert!(provider.lookup(&unknown).unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::io::Error;
+use std::sync::Arc;
+
+use mac_address::MacAddress;
+
+use crate::config::parse_hex;
+use crate::config::parse_uuid;
+use crate::config::HostConfig;
+use crate::config::ProviderConfig;
+use crate::config::ServerConfig;
+use crate::state::StateStore;
+
+mod inventory;
+mod sqlite;
+mod static_file;
+
+pub use inventory::InventoryProvider;
+pub use sqlite::SqliteProvider;
+pub use static_file::StaticFileProvider;
+
+/// What a client is known by when its host entry is looked up
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct HostKey {
+    pub mac: Option<MacAddress>,
+    pub client_id: Option<Vec<u8>>,
+    /// The DUID of a DHCPv6 client
+    pub duid: Option<Vec<u8>>,
+    /// The machine UUIDs to try, as the client sent it first
+    pub uuids: Vec<[u8; 16]>,
+}
+
+/// A source of host entries.  Ok(None) means the source does not know
+/// the client; an error means it could not be asked.
+pub trait HostProvider: Send + Sync {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String>;
+}
+
+/// The host for a key in a list: by machine UUID first, then by DUID or
+/// client identifier, then by MAC address.
+pub fn find_host<'a>(hosts: &'a [HostConfig], key: &HostKey) -> Option<&'a HostConfig> {
+    let by_uuid = hosts.iter().find(|host| match &host.uuid {
+        Some(text) => match parse_uuid(text) {
+            Ok(uuid) => key.uuids.contains(&uuid),
+            Err(_) => false
+        },
+        None => false
+    });
+    let by_duid = || hosts.iter().find(|host| match (&host.duid, &key.duid) {
+        (Some(text), Some(duid)) => parse_hex(text).as_ref() == Ok(duid),
+        _ => false
+    });
+    let by_client_id = || hosts.iter().find(|host| match (&host.client_id, &key.client_id) {
+        (Some(text), Some(client_id)) => parse_hex(text).as_ref() == Ok(client_id),
+        _ => false
+    });
+    let by_mac = || hosts.iter().find(|host| host.mac.is_some() && host.mac == key.mac);
+    by_uuid.or_else(by_duid).or_else(by_client_id).or_else(by_mac)
+}
+
+/// The hosts of the config file
+pub struct ConfigProvider {
+    hosts: Vec<HostConfig>,
+}
+
+impl ConfigProvider {
+    pub fn new(config: &ServerConfig) -> ConfigProvider {
+        ConfigProvider { hosts: config.hosts.clone() }
+    }
+}
+
+impl HostProvider for ConfigProvider {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        Ok(find_host(&self.hosts, key).cloned())
+    }
+}
+
+/// The hosts operators approved from discovery
+pub struct ApprovedProvider {
+    states: Arc<StateStore>,
+}
+
+impl HostProvider for ApprovedProvider {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        Ok(find_host(&self.states.approved_hosts(), key).cloned())
+    }
+}
+
+/// Keeps the hosts of another provider out of the dynamic pools, which
+/// only leave out the hosts of the config file.  A host reserving a pool
+/// address could be given the same address as a dynamic client, so it is
+/// refused.
+struct OutsidePools {
+    provider: Box<dyn HostProvider>,
+    config: Arc<ServerConfig>,
+}
+
+impl HostProvider for OutsidePools {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        match self.provider.lookup(key)? {
+            Some(host) if self.config.reserves_pool_address(&host) =>
+                Err(format!("host for {:?} reserves an address in a dynamic pool", key)),
+            host => Ok(host)
+        }
+    }
+}
+
+/// Asks each provider in turn.  One that fails is logged and skipped,
+/// so an unreachable inventory does not stop the others from answering.
+pub struct ChainProvider {
+    providers: Vec<Box<dyn HostProvider>>,
+}
+
+impl HostProvider for ChainProvider {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        for provider in &self.providers {
+            match provider.lookup(key) {
+                Ok(Some(host)) => return Ok(Some(host)),
+                Ok(None) => {},
+                Err(e) => println!("host lookup failed: {}", e)
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The hosts in the config file come first, then the approved ones, then
+/// the configured backend.  Hosts other than the config file's have to
+/// reserve addresses outside the dynamic pools.
+pub fn from_config(config: &ServerConfig,
+                   states: Arc<StateStore>) -> Result<Arc<dyn HostProvider>, Error> {
+    let backend: Option<Box<dyn HostProvider>> = match &config.provider {
+        ProviderConfig::Config => None,
+        ProviderConfig::File { path } =>
+            Some(Box::new(StaticFileProvider::new(path))),
+        ProviderConfig::Sqlite { path } =>
+            Some(Box::new(SqliteProvider::open(path).map_err(Error::other)?)),
+        ProviderConfig::Http { url, timeout_secs } =>
+            Some(Box::new(InventoryProvider::new(url, *timeout_secs))),
+    };
+    let shared = Arc::new(config.clone());
+    let outside_pools = |provider| -> Box<dyn HostProvider> {
+        Box::new(OutsidePools { provider, config: shared.clone() })
+    };
+    let mut providers: Vec<Box<dyn HostProvider>> = vec![
+        Box::new(ConfigProvider::new(config)),
+        outside_pools(Box::new(ApprovedProvider { states })),
+    ];
+    providers.extend(backend.map(outside_pools));
+    Ok(Arc::new(ChainProvider { providers }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts() -> Vec<HostConfig> {
+        ServerConfig::parse(
+            "[[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             hostname = \"by-mac\"\n\
+             [[hosts]]\n\
+             client_id = \"ff:00:01\"\n\
+             hostname = \"by-client-id\"\n\
+             [[hosts]]\n\
+             duid = \"00:03:00:01:52:54:00:94:9e:f2\"\n\
+             hostname = \"by-duid\"\n\
+             [[hosts]]\n\
+             uuid = \"384c23b2-c3e1-45ad-b797-d2dd220e1b9d\"\n\
+             hostname = \"by-uuid\"\n").unwrap().hosts
+    }
+
+    fn hostname(host: Option<&HostConfig>) -> Option<&str> {
+        host.and_then(|host| host.hostname.as_deref())
+    }
+
+    #[test]
+    fn test_find_host_order() {
+        let hosts = hosts();
+        let mut key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        assert_eq!(Some("by-mac"), hostname(find_host(&hosts, &key)));
+        key.client_id = Some(vec![0xff, 0, 1]);
+        assert_eq!(Some("by-client-id"), hostname(find_host(&hosts, &key)));
+        key.duid = Some(vec![0, 3, 0, 1, 0x52, 0x54, 0, 0x94, 0x9e, 0xf2]);
+        assert_eq!(Some("by-duid"), hostname(find_host(&hosts, &key)));
+        key.uuids = vec![parse_uuid("384c23b2-c3e1-45ad-b797-d2dd220e1b9d").unwrap()];
+        assert_eq!(Some("by-uuid"), hostname(find_host(&hosts, &key)));
+        assert_eq!(None, hostname(find_host(&hosts, &HostKey::default())));
+    }
+
+    struct FailingProvider;
+
+    impl HostProvider for FailingProvider {
+        fn lookup(&self, _key: &HostKey) -> Result<Option<HostConfig>, String> {
+            Err("unreachable".to_string())
+        }
+    }
+
+    #[test]
+    fn test_chain_skips_failures() {
+        let config = ServerConfig { hosts: hosts(), ..ServerConfig::default() };
+        let chain = ChainProvider {
+            providers: vec![Box::new(FailingProvider), Box::new(ConfigProvider::new(&config))]
+        };
+        let key = HostKey { client_id: Some(vec![0xff, 0, 1]), ..HostKey::default() };
+        assert_eq!(Some("by-client-id".to_string()),
+                   chain.lookup(&key).unwrap().and_then(|host| host.hostname));
+    }
+
+    #[test]
+    fn test_reservations_outside_pools() {
+        let config = ServerConfig::parse(
+            "[[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             ip = \"192.168.144.150\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f3\"\n\
+             ip = \"192.168.144.50\"\n").unwrap();
+        let provider = OutsidePools {
+            provider: Box::new(ConfigProvider::new(&config)),
+            config: Arc::new(ServerConfig::default()),
+        };
+        let key = |last| HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, last])),
+            ..HostKey::default()
+        };
+        assert!(provider.lookup(&key(0xf2)).is_err());
+        assert!(provider.lookup(&key(0xf3)).unwrap().is_some());
+        assert!(provider.lookup(&key(0xf4)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,230 @@
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use rusqlite::params;
+use rusqlite::types::Type;
+use rusqlite::Connection;
+use rusqlite::Row;
+
+use crate::config::HostConfig;
+
+use super::find_host;
+use super::HostKey;
+use super::HostProvider;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS hosts (
+    mac TEXT,
+    client_id TEXT,
+    uuid TEXT,
+    ip TEXT,
+    hostname TEXT,
+    next_server TEXT,
+    tftp_server_name TEXT,
+    boot_file TEXT,
+    kernel TEXT,
+    initrd TEXT,
+    kernel_args TEXT NOT NULL DEFAULT '',
+    duid TEXT,
+    config TEXT
+)";
+
+// Columns a table created by an older version does not have yet
+const ADDED_COLUMNS: &[(&str, &str)] = &[("duid", "TEXT"), ("config", "TEXT")];
+
+// Identifiers are compared as bare lower case hex, however they were
+// written into the table.
+const LOOKUP: &str = "SELECT mac, client_id, uuid, ip, hostname, next_server, \
+    tftp_server_name, boot_file, kernel, initrd, kernel_args, duid, config FROM hosts \
+    WHERE lower(replace(replace(mac, ':', ''), '-', '')) = ?1 \
+    OR lower(replace(replace(client_id, ':', ''), '-', '')) = ?2 \
+    OR lower(replace(uuid, '-', '')) IN (?3, ?4) \
+    OR lower(replace(replace(duid, ':', ''), '-', '')) = ?5";
+
+/// Hosts kept in a SQLite database, one row each.  The config column
+/// holds the rest of a host entry as JSON, in the fields of the config
+/// file; the other columns override it.
+pub struct SqliteProvider {
+    connection: Mutex<Connection>,
+}
+
+fn parse_column<T: FromStr>(row: &Row, index: usize) -> rusqlite::Result<Option<T>> {
+    match row.get::<_, Option<String>>(index)? {
+        Some(text) if text.is_empty() => Ok(None),
+        Some(text) => text.parse().map(Some).map_err(|_| rusqlite::Error::InvalidColumnType(
+            index, text, rusqlite::types::Type::Text)),
+        None => Ok(None)
+    }
+}
+
+fn host_from_row(row: &Row) -> rusqlite::Result<HostConfig> {
+    let mut host: HostConfig = match row.get::<_, Option<String>>(12)? {
+        Some(json) if !json.trim().is_empty() => serde_json::from_str(&json).
+            map_err(|e| rusqlite::Error::FromSqlConversionFailure(12, Type::Text, Box::new(e)))?,
+        _ => HostConfig::default()
+    };
+    host.mac = parse_column(row, 0)?.or(host.mac);
+    host.client_id = row.get::<_, Option<String>>(1)?.or(host.client_id);
+    host.uuid = row.get::<_, Option<String>>(2)?.or(host.uuid);
+    host.ip = parse_column::<Ipv4Addr>(row, 3)?.or(host.ip);
+    host.hostname = row.get::<_, Option<String>>(4)?.or(host.hostname);
+    host.next_server = parse_column::<Ipv4Addr>(row, 5)?.or(host.next_server);
+    host.tftp_server_name = row.get::<_, Option<String>>(6)?.or(host.tftp_server_name);
+    host.boot_file = row.get::<_, Option<String>>(7)?.or(host.boot_file);
+    host.kernel = row.get::<_, Option<String>>(8)?.or(host.kernel);
+    host.initrd = row.get::<_, Option<String>>(9)?.or(host.initrd);
+    let kernel_args: String = row.get(10)?;
+    if !kernel_args.is_empty() {
+        host.kernel_args = kernel_args;
+    }
+    host.duid = row.get::<_, Option<String>>(11)?.or(host.duid);
+    Ok(host)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl SqliteProvider {
+    /// Opens the database, creating the hosts table if it is missing and
+    /// adding any columns it lacks.
+    pub fn open(path: &str) -> Result<SqliteProvider, String> {
+        let error = |e: rusqlite::Error| format!("{}: {}", path, e);
+        let connection = Connection::open(path).map_err(error)?;
+        connection.execute(SCHEMA, []).map_err(error)?;
+        let columns = connection.prepare("SELECT name FROM pragma_table_info('hosts')").
+            and_then(|mut statement| statement.query_map([], |row| row.get::<_, String>(0))?.
+                     collect::<rusqlite::Result<Vec<String>>>()).
+            map_err(error)?;
+        for (name, column_type) in ADDED_COLUMNS {
+            if !columns.iter().any(|column| column == name) {
+                connection.execute(&format!("ALTER TABLE hosts ADD COLUMN {} {}", name, column_type), []).
+                    map_err(error)?;
+            }
+        }
+        Ok(SqliteProvider { connection: Mutex::new(connection) })
+    }
+}
+
+impl HostProvider for SqliteProvider {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        let mac = key.mac.map(|mac| hex(&mac.bytes())).unwrap_or_default();
+        let client_id = key.client_id.as_deref().map(hex).unwrap_or_default();
+        let uuid = |index: usize| key.uuids.get(index).map(|uuid| hex(uuid)).unwrap_or_default();
+        let duid = key.duid.as_deref().map(hex).unwrap_or_default();
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(LOOKUP).map_err(|e| e.to_string())?;
+        let rows = statement.query_map(params![mac, client_id, uuid(0), uuid(1), duid], host_from_row).
+            map_err(|e| e.to_string())?;
+        // A bad row is logged and skipped, so that it does not hide the
+        // others from the lookup.
+        let hosts: Vec<HostConfig> = rows.filter_map(|row| {
+            let host = row.map_err(|e| e.to_string()).
+                and_then(|host| host.check().map(|()| host).map_err(String::from));
+            host.map_err(|e| println!("skipping host row for {:?}: {}", key, e)).ok()
+        }).collect();
+        Ok(find_host(&hosts, key).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mac_address::MacAddress;
+
+    use crate::config::parse_uuid;
+
+    #[test]
+    fn test_lookup() {
+        let provider = SqliteProvider::open(":memory:").unwrap();
+        provider.connection.lock().unwrap().execute_batch(
+            "INSERT INTO hosts (mac, ip, hostname) \
+             VALUES ('52:54:00:94:9E:F2', '10.0.0.5', 'by-mac');
+             INSERT INTO hosts (uuid, hostname, kernel_args) \
+             VALUES ('384c23b2-c3e1-45ad-b797-d2dd220e1b9d', 'by-uuid', 'console=ttyS0');").
+            unwrap();
+
+        let mut key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        let host = provider.lookup(&key).unwrap().unwrap();
+        assert_eq!(Some("by-mac".to_string()), host.hostname);
+        assert_eq!(Some(Ipv4Addr::new(10, 0, 0, 5)), host.ip);
+
+        key.uuids = vec![parse_uuid("384c23b2-c3e1-45ad-b797-d2dd220e1b9d").unwrap()];
+        let host = provider.lookup(&key).unwrap().unwrap();
+        assert_eq!(Some("by-uuid".to_string()), host.hostname);
+        assert_eq!("console=ttyS0", host.kernel_args);
+
+        assert!(provider.lookup(&HostKey::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_config_column() {
+        let provider = SqliteProvider::open(":memory:").unwrap();
+        provider.connection.lock().unwrap().execute_batch(
+            "INSERT INTO hosts (mac, hostname, config) \
+             VALUES ('52:54:00:94:9e:f2', 'by-column', '{\"hostname\": \"by-config\", \
+                     \"state\": \"install\", \"token\": \"s3cret\", \"classes\": [\"web\"], \
+                     \"secrets\": {\"root\": \"$6$hash\"}, \"vars\": {\"disk\": \"sda\"}}');
+             INSERT INTO hosts (duid, config) \
+             VALUES ('00:03:00:01:52:54:00:00:00:01', '{\"ipv6\": \"fd00::5\"}');").
+            unwrap();
+        let key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        let host = provider.lookup(&key).unwrap().unwrap();
+        assert_eq!(Some("by-column".to_string()), host.hostname);
+        assert_eq!(Some(crate::state::BootState::Install), host.state);
+        assert_eq!(Some("s3cret".to_string()), host.token);
+        assert_eq!(vec!["web".to_string()], host.classes);
+        assert_eq!(Some("$6$hash"), host.secrets.get("root").map(String::as_str));
+        assert_eq!(Some(&serde_json::json!("sda")), host.vars.get("disk"));
+
+        let key = HostKey { duid: Some(vec![0, 3, 0, 1, 0x52, 0x54, 0, 0, 0, 1]), ..HostKey::default() };
+        let host = provider.lookup(&key).unwrap().unwrap();
+        assert_eq!(Some("fd00::5".parse().unwrap()), host.ipv6);
+    }
+
+    #[test]
+    fn test_bad_rows_skipped() {
+        let provider = SqliteProvider::open(":memory:").unwrap();
+        provider.connection.lock().unwrap().execute_batch(
+            "INSERT INTO hosts (mac, ip, hostname) VALUES ('52:54:00:94:9e:f2', '10.0.0', 'bad-ip');
+             INSERT INTO hosts (mac, config) VALUES ('52:54:00:94:9e:f2', '{\"hostname\": ');
+             INSERT INTO hosts (mac, client_id) VALUES ('52:54:00:94:9e:f2', 'zz');
+             INSERT INTO hosts (uuid, hostname) \
+             VALUES ('384c23b2-c3e1-45ad-b797-d2dd220e1b9d', 'good');").
+            unwrap();
+        let mut key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        assert!(provider.lookup(&key).unwrap().is_none());
+        key.uuids = vec![parse_uuid("384c23b2-c3e1-45ad-b797-d2dd220e1b9d").unwrap()];
+        assert_eq!(Some("good".to_string()), provider.lookup(&key).unwrap().unwrap().hostname);
+    }
+
+    #[test]
+    fn test_old_table_gets_new_columns() {
+        let path = std::env::temp_dir().
+            join(format!("rustboot-hosts-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        Connection::open(path).unwrap().execute_batch(
+            "CREATE TABLE hosts (mac TEXT, client_id TEXT, uuid TEXT, ip TEXT, hostname TEXT, \
+             next_server TEXT, tftp_server_name TEXT, boot_file TEXT, kernel TEXT, initrd TEXT, \
+             kernel_args TEXT NOT NULL DEFAULT '');
+             INSERT INTO hosts (mac, hostname) VALUES ('52:54:00:94:9e:f2', 'old');").unwrap();
+        let provider = SqliteProvider::open(path).unwrap();
+        let key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        assert_eq!(Some("old".to_string()), provider.lookup(&key).unwrap().unwrap().hostname);
+        drop(provider);
+        assert!(SqliteProvider::open(path).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::config::HostConfig;
+
+use super::find_host;
+use super::HostKey;
+use super::HostProvider;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HostsFile {
+    #[serde(default)]
+    hosts: Vec<HostConfig>,
+}
+
+/// Hosts kept in a TOML file of their own, in the same [[hosts]] form
+/// as the config file.  The file is read again whenever it changes, so
+/// it can be edited without restarting the server.
+pub struct StaticFileProvider {
+    path: String,
+    cache: Mutex<Option<(SystemTime, Vec<HostConfig>)>>,
+}
+
+impl StaticFileProvider {
+    pub fn new(path: &str) -> StaticFileProvider {
+        StaticFileProvider { path: path.to_string(), cache: Mutex::new(None) }
+    }
+
+    fn load(&self) -> Result<Vec<HostConfig>, String> {
+        let text = fs::read_to_string(&self.path).
+            map_err(|e| format!("{}: {}", self.path, e))?;
+        let file: HostsFile = toml::from_str(&text).
+            map_err(|e| format!("{}: {}", self.path, e))?;
+        for host in &file.hosts {
+            host.check().map_err(|e| format!("{}: {}", self.path, e))?;
+        }
+        Ok(file.hosts)
+    }
+}
+
+impl HostProvider for StaticFileProvider {
+    fn lookup(&self, key: &HostKey) -> Result<Option<HostConfig>, String> {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).
+            map_err(|e| format!("{}: {}", self.path, e))?;
+        let mut cache = self.cache.lock().unwrap();
+        match &*cache {
+            Some((loaded, _)) if *loaded == modified => {},
+            _ => *cache = Some((modified, self.load()?)),
+        }
+        let hosts = &cache.as_ref().unwrap().1;
+        Ok(find_host(hosts, key).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use mac_address::MacAddress;
+
+    #[test]
+    fn test_reloads_changed_file() {
+        let path = std::env::temp_dir().
+            join(format!("rustboot-hosts-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "[[hosts]]\nmac = \"52:54:00:94:9e:f2\"\nhostname = \"one\"\n").unwrap();
+        let provider = StaticFileProvider::new(path);
+        let key = HostKey {
+            mac: Some(MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])),
+            ..HostKey::default()
+        };
+        let hostname = || provider.lookup(&key).unwrap().and_then(|host| host.hostname);
+        assert_eq!(Some("one".to_string()), hostname());
+
+        fs::write(path, "[[hosts]]\nmac = \"52:54:00:94:9e:f2\"\nhostname = \"two\"\n").unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(Some("two".to_string()), hostname());
+
+        fs::write(path, "[[hosts]]\nhostname = \"nobody\"\n").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(20)).unwrap();
+        assert!(provider.lookup(&key).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+use minijinja::{context, path_loader, Environment, UndefinedBehavior};
+
+use crate::config::mac_path;
+use crate::config::ServerConfig;
+use crate::dhcp::MachineConfig;
+
+/// The installer configs that can be rendered for a machine
+pub const FILES: [&str; 5] = ["kickstart", "preseed", "autoinstall", "user-data", "meta-data"];
+
+// cloud-init NoCloud needs a meta-data file even when there is nothing
+// in it worth a template of its own.
+const DEFAULT_META_DATA: &str = include_str!("../templates/meta-data");
+
+/// Renders one of FILES for a machine, or None if there is no template
+/// for it.  The URL token, if any, is put in the URLs of the machine's
+/// secrets, and is there for templates to put in their own URLs.  A
+/// variable that is neither set nor given a default with the default
+/// filter is an error rather than an empty string, so that a broken
+/// installer config is never served.
+pub fn render(config: &ServerConfig, machine: &MachineConfig, file: &str,
+              url_token: Option<&str>) -> Result<Option<String>, String> {
+    if !FILES.contains(&file) {
+        return Ok(None);
+    }
+    let mut env = Environment::new();
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    let loader = config.provision.template_dir.as_ref().map(path_loader);
+    env.set_loader(move |name| {
+        let source = match &loader {
+            Some(loader) => loader(name)?,
+            None => None
+        };
+        Ok(source.or_else(|| if name == "meta-data" {
+            Some(DEFAULT_META_DATA.to_string())
+        } else {
+            None
+        }))
+    });
+    let template = match env.get_template(file) {
+        Ok(template) => template,
+        Err(e) if e.kind() == minijinja::ErrorKind::TemplateNotFound => return Ok(None),
+        Err(e) => return Err(format!("{:#}", e))
+    };
+
+    let host = machine.host();
+    let mut vars = config.provision.vars.clone();
+    if let Some(host) = host {
+        vars.extend(host.vars.clone());
+    }
+    let secrets: BTreeMap<&str, String> = host.iter().flat_map(|host| host.secrets.keys()).
+        map(|name| {
+            let url = config.ipxe.secret_url(&machine.mac_address, name);
+            (name.as_str(), match url_token {
+                Some(token) => format!("{}?token={}", url, token),
+                None => url
+            })
+        }).collect();
+    let dns: Vec<String> = machine.dns_addresses().iter().map(|addr| addr.to_string()).collect();
+    template.render(context!{
+        mac => mac_path(&machine.mac_address),
+        hostname => host.and_then(|host| host.hostname.clone()),
+        ip => machine.reserved_ip().map(|ip| ip.to_string()),
+        netmask => machine.subnet_mask().to_string(),
+        gateway => machine.router().to_string(),
+        dns => dns,
+        domain_search => machine.domain_search(),
+        server_ip => config.server_ip.to_string(),
+        state => machine.state.map(|state| state.name()),
+        callback_url => config.ipxe.callback_url(&machine.mac_address),
+        token => host.and_then(|host| host.token.clone()),
+        url_token => url_token,
+        secrets => secrets,
+        host => host,
+        vars => vars,
+    }).map(Some).map_err(|e| format!("{:#}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use mac_address::MacAddress;
+
+    use crate::config::HostConfig;
+    use crate::provider::{find_host, HostKey};
+
+    fn mac() -> MacAddress {
+        MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])
+    }
+
+    fn host(config: &ServerConfig) -> Option<HostConfig> {
+        find_host(&config.hosts, &HostKey { mac: Some(mac()), ..HostKey::default() }).cloned()
+    }
+
+    fn template_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = std::env::temp_dir().
+            join(format!("rustboot-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, text) in files {
+            fs::write(dir.join(file), text).unwrap();
+        }
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn config(dir: &str) -> ServerConfig {
+        ServerConfig::parse(&format!(
+            "[provision]\n\
+             template_dir = \"{}\"\n\
+             vars = {{ timezone = \"UTC\", disk = \"sda\" }}\n\
+             [[subnets]]\n\
+             network = \"10.0.0.0/24\"\n\
+             router = \"10.0.0.1\"\n\
+             [[hosts]]\n\
+             mac = \"52:54:00:94:9e:f2\"\n\
+             ip = \"10.0.0.5\"\n\
+             hostname = \"web1\"\n\
+             vars = {{ disk = \"nvme0n1\" }}\n", dir)).unwrap()
+    }
+
+    #[test]
+    fn test_render_kickstart() {
+        let dir = template_dir("kickstart", &[
+            ("kickstart", "{% include \"network.inc\" %}\n\
+                           timezone {{ vars.timezone }}\n\
+                           ignoredisk --only-use={{ vars.disk }}\n\
+                           lang {{ vars.lang | default(\"en_US.UTF-8\") }}\n"),
+            ("network.inc", "network --ip={{ ip }} --netmask={{ netmask }} \
+                             --gateway={{ gateway }} --hostname={{ hostname }}\n"),
+        ]);
+        let config = config(&dir);
+        let machine = MachineConfig::for_machine(&config, mac(), host(&config), None);
+        assert_eq!(Some("network --ip=10.0.0.5 --netmask=255.255.255.0 \
+                         --gateway=10.0.0.1 --hostname=web1\n\
+                         timezone UTC\n\
+                         ignoredisk --only-use=nvme0n1\n\
+                         lang en_US.UTF-8\n".to_string()),
+                   render(&config, &machine, "kickstart", None).unwrap());
+        assert_eq!(None, render(&config, &machine, "preseed", None).unwrap());
+        assert_eq!(None, render(&config, &machine, "network.inc", None).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_undefined_variable_is_an_error() {
+        let dir = template_dir("undefined", &[("preseed", "d-i passwd/root-password {{ vars.password }}\n")]);
+        let config = config(&dir);
+        let machine = MachineConfig::for_machine(&config, mac(), host(&config), None);
+        let error = render(&config, &machine, "preseed", None).unwrap_err();
+        assert!(error.contains("undefined"), "{}", error);
+        assert!(error.contains("preseed"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_meta_data() {
+        let config = ServerConfig::default();
+        let machine = MachineConfig::for_machine(&config, mac(), None, None);
+        assert_eq!(Some("instance-id: 52:54:00:94:9e:f2\n".to_string()),
+                   render(&config, &machine, "meta-data", None).unwrap());
+        assert_eq!(None, render(&config, &machine, "user-data", None).unwrap());
+    }
+}
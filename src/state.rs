@@ -0,0 +1,376 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use mac_address::MacAddress;
+use serde::{Deserialize, Serialize};
+
+use crate::config::mac_path;
+use crate::config::HostConfig;
+use crate::discovery::HardwareFacts;
+use crate::discovery::PendingMachine;
+
+/// Where a machine is in its provisioning, which decides what it boots.
+/// A machine that has been installed boots from its local disk, so the
+/// next reboot does not run the installer again.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootState {
+    Discover,
+    Install,
+    #[serde(alias = "installed")]
+    Localboot,
+    Rescue,
+}
+
+impl BootState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootState::Discover => "discover",
+            BootState::Install => "install",
+            BootState::Localboot => "localboot",
+            BootState::Rescue => "rescue",
+        }
+    }
+}
+
+impl fmt::Display for BootState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for BootState {
+    type Err = &'static str;
+
+    fn from_str(text: &str) -> Result<BootState, &'static str> {
+        match text.trim() {
+            "discover" => Ok(BootState::Discover),
+            "install" => Ok(BootState::Install),
+            "localboot" | "installed" => Ok(BootState::Localboot),
+            "rescue" => Ok(BootState::Rescue),
+            _ => Err("unknown boot state")
+        }
+    }
+}
+
+// Only the latest reports of a machine are kept
+const MAX_REPORTS: usize = 32;
+// The machines kept waiting for approval; past this the one heard from
+// longest ago makes room
+const MAX_PENDING: usize = 1000;
+
+/// A stage an installer reported reaching, such as "started" or "done"
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Report {
+    pub stage: String,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+/// The state recorded for a machine, a state for its next boot only,
+/// and what its installer has reported.  Without a recorded state the
+/// host's configured one applies.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct MachineState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<BootState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub once: Option<BootState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reports: Vec<Report>,
+    /// The names of the one-time secrets already given out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub used_secrets: Vec<String>,
+    /// What the discovery image reported, until an operator decides
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending: Option<PendingMachine>,
+    /// The host entry an operator approved the machine into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostConfig>,
+}
+
+impl MachineState {
+    fn is_empty(&self) -> bool {
+        self.state.is_none() && self.once.is_none() && self.reports.is_empty() &&
+            self.used_secrets.is_empty() && self.pending.is_none() && self.host.is_none()
+    }
+
+    /// The state the next boot uses
+    pub fn effective(&self, configured: Option<BootState>) -> Option<BootState> {
+        self.once.or(self.state).or(configured)
+    }
+}
+
+/// The boot states and installer reports of all machines, shared by the DHCP and HTTP servers
+/// and written to the state file, when there is one, on every change.
+#[derive(Default)]
+pub struct StateStore {
+    path: Option<String>,
+    machines: Mutex<BTreeMap<String, MachineState>>,
+}
+
+impl StateStore {
+    pub fn open(path: Option<&str>) -> Result<StateStore, Error> {
+        let machines = match path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).
+                    map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+                Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e)
+            },
+            None => BTreeMap::new()
+        };
+        Ok(StateStore { path: path.map(|path| path.to_string()), machines: Mutex::new(machines) })
+    }
+
+    fn save(&self, machines: &BTreeMap<String, MachineState>) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let text = serde_json::to_string_pretty(machines).map_err(Error::other)?;
+            let temp = format!("{}.tmp", path);
+            fs::write(&temp, text)?;
+            fs::rename(&temp, path)?;
+        }
+        Ok(())
+    }
+
+    fn update(&self, mac: &MacAddress,
+              change: impl FnOnce(&mut MachineState)) -> Result<MachineState, Error> {
+        let mut machines = self.machines.lock().unwrap();
+        let machine = machines.entry(mac_path(mac)).or_default();
+        change(machine);
+        let machine = machine.clone();
+        if machine.is_empty() {
+            machines.remove(&mac_path(mac));
+        }
+        self.save(&machines)?;
+        Ok(machine)
+    }
+
+    pub fn get(&self, mac: &MacAddress) -> MachineState {
+        self.machines.lock().unwrap().get(&mac_path(mac)).cloned().unwrap_or_default()
+    }
+
+    /// Records the state of a machine, or forgets it, leaving the host's
+    /// configured state.  A pending one-shot state still comes first.
+    pub fn set(&self, mac: &MacAddress, state: Option<BootState>) -> Result<MachineState, Error> {
+        self.update(mac, |machine| machine.state = state)
+    }
+
+    /// Sets or clears a state for the next boot only
+    pub fn set_once(&self, mac: &MacAddress,
+                    once: Option<BootState>) -> Result<MachineState, Error> {
+        self.update(mac, |machine| machine.once = once)
+    }
+
+    /// Records a report from the machine's installer.  A report can move
+    /// the machine to a new state, which also drops any one-shot state.
+    pub fn report(&self, mac: &MacAddress, report: Report,
+                  next: Option<BootState>) -> Result<MachineState, Error> {
+        self.update(mac, |machine| {
+            machine.reports.push(report);
+            if machine.reports.len() > MAX_REPORTS {
+                machine.reports.remove(0);
+            }
+            if next.is_some() {
+                machine.state = next;
+                machine.once = None;
+            }
+        })
+    }
+
+    /// Marks a one-time secret as given out.  False if it already was.
+    pub fn use_secret(&self, mac: &MacAddress, name: &str) -> Result<bool, Error> {
+        let mut unused = false;
+        self.update(mac, |machine| {
+            unused = !machine.used_secrets.iter().any(|used| used == name);
+            if unused {
+                machine.used_secrets.push(name.to_string());
+            }
+        })?;
+        Ok(unused)
+    }
+
+    /// Lets the machine's secrets be fetched again, as for a reinstall
+    pub fn rearm_secrets(&self, mac: &MacAddress) -> Result<MachineState, Error> {
+        self.update(mac, |machine| machine.used_secrets.clear())
+    }
+
+    /// Records the facts an unknown machine reported
+    pub fn report_facts(&self, mac: &MacAddress, facts: HardwareFacts,
+                        now: u64) -> Result<MachineState, Error> {
+        self.make_room_for_pending(mac);
+        self.update(mac, |machine| {
+            let first_reported = machine.pending.as_ref().map_or(now, |pending| pending.first_reported);
+            machine.pending = Some(PendingMachine { facts, first_reported, last_reported: now });
+        })
+    }
+
+    fn make_room_for_pending(&self, mac: &MacAddress) {
+        let mut machines = self.machines.lock().unwrap();
+        let pending = || machines.iter().filter_map(|(mac, machine)| {
+            machine.pending.as_ref().map(|pending| (mac, pending.last_reported))
+        });
+        let is_pending = machines.get(&mac_path(mac)).is_some_and(|machine| machine.pending.is_some());
+        if is_pending || pending().count() < MAX_PENDING {
+            return;
+        }
+        let oldest = pending().min_by_key(|(_, last_reported)| *last_reported).
+            map(|(mac, _)| mac.clone());
+        if let Some(oldest) = oldest {
+            if let Some(machine) = machines.get_mut(&oldest) {
+                machine.pending = None;
+                if machine.is_empty() {
+                    machines.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// The machines waiting for approval, by MAC address
+    pub fn pending(&self) -> BTreeMap<String, PendingMachine> {
+        self.machines.lock().unwrap().iter().filter_map(|(mac, machine)| {
+            machine.pending.clone().map(|pending| (mac.clone(), pending))
+        }).collect()
+    }
+
+    /// Makes a pending machine a host, or with no host, rejects it
+    pub fn approve(&self, mac: &MacAddress,
+                   host: Option<HostConfig>) -> Result<Option<MachineState>, Error> {
+        let mut machines = self.machines.lock().unwrap();
+        let machine = match machines.get_mut(&mac_path(mac)) {
+            Some(machine) if machine.pending.is_some() => machine,
+            _ => return Ok(None)
+        };
+        machine.pending = None;
+        if host.is_some() {
+            machine.host = host;
+        }
+        let machine = machine.clone();
+        if machine.is_empty() {
+            machines.remove(&mac_path(mac));
+        }
+        self.save(&machines)?;
+        Ok(Some(machine))
+    }
+
+    /// The host entries operators have approved
+    pub fn approved_hosts(&self) -> Vec<HostConfig> {
+        self.machines.lock().unwrap().values().
+            filter_map(|machine| machine.host.clone()).collect()
+    }
+
+    /// The state a boot uses.  A one-shot state is used up by it.
+    pub fn boot(&self, mac: &MacAddress,
+                configured: Option<BootState>) -> Result<Option<BootState>, Error> {
+        let state = self.get(mac);
+        if state.once.is_some() {
+            self.set_once(mac, None)?;
+        }
+        Ok(state.effective(configured))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac() -> MacAddress {
+        MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])
+    }
+
+    #[test]
+    fn test_one_shot_reverts() {
+        let store = StateStore::default();
+        assert_eq!(Some(BootState::Install), store.get(&mac()).effective(Some(BootState::Install)));
+        store.set(&mac(), Some(BootState::Localboot)).unwrap();
+        store.set_once(&mac(), Some(BootState::Rescue)).unwrap();
+        assert_eq!(Some(BootState::Rescue), store.boot(&mac(), None).unwrap());
+        assert_eq!(Some(BootState::Localboot), store.boot(&mac(), None).unwrap());
+        store.set(&mac(), None).unwrap();
+        assert_eq!(None, store.boot(&mac(), None).unwrap());
+    }
+
+    #[test]
+    fn test_reports() {
+        let store = StateStore::default();
+        store.set_once(&mac(), Some(BootState::Rescue)).unwrap();
+        let report = |stage: &str| Report { stage: stage.to_string(), time: 1, message: String::new() };
+        store.report(&mac(), report("started"), None).unwrap();
+        assert_eq!(Some(BootState::Rescue), store.get(&mac()).once);
+        for _ in 0..MAX_REPORTS {
+            store.report(&mac(), report("progress"), None).unwrap();
+        }
+        let machine = store.report(&mac(), report("done"), Some(BootState::Localboot)).unwrap();
+        assert_eq!(MAX_REPORTS, machine.reports.len());
+        assert_eq!("done", machine.reports.last().unwrap().stage);
+        assert_eq!(Some(BootState::Localboot), machine.effective(Some(BootState::Install)));
+    }
+
+    #[test]
+    fn test_one_time_secrets() {
+        let store = StateStore::default();
+        assert!(store.use_secret(&mac(), "root").unwrap());
+        assert!(!store.use_secret(&mac(), "root").unwrap());
+        assert!(store.use_secret(&mac(), "key").unwrap());
+        store.rearm_secrets(&mac()).unwrap();
+        assert!(store.use_secret(&mac(), "root").unwrap());
+    }
+
+    #[test]
+    fn test_approve_pending() {
+        let store = StateStore::default();
+        assert_eq!(None, store.approve(&mac(), None).unwrap().map(|machine| machine.state));
+        store.report_facts(&mac(), HardwareFacts::default(), 10).unwrap();
+        let machine = store.report_facts(&mac(), HardwareFacts::default(), 20).unwrap();
+        let pending = machine.pending.unwrap();
+        assert_eq!((10, 20), (pending.first_reported, pending.last_reported));
+        assert_eq!(vec!["52:54:00:94:9e:f2"], store.pending().into_keys().collect::<Vec<_>>());
+
+        let host = HostConfig { mac: Some(mac()), hostname: Some("new".to_string()),
+                                ..HostConfig::default() };
+        store.approve(&mac(), Some(host)).unwrap().unwrap();
+        assert!(store.pending().is_empty());
+        assert_eq!(Some("new".to_string()), store.approved_hosts()[0].hostname);
+    }
+
+    #[test]
+    fn test_oldest_pending_evicted() {
+        let store = StateStore::default();
+        let nth = |n: usize| MacAddress::new([0x52, 0x54, 0, 0, (n >> 8) as u8, n as u8]);
+        for n in 0..MAX_PENDING {
+            store.report_facts(&nth(n), HardwareFacts::default(), 100 + n as u64).unwrap();
+        }
+        store.report_facts(&nth(0), HardwareFacts::default(), 5000).unwrap();
+        store.report_facts(&mac(), HardwareFacts::default(), 5001).unwrap();
+        let pending = store.pending();
+        assert_eq!(MAX_PENDING, pending.len());
+        assert!(!pending.contains_key("52:54:00:00:00:01"));
+        assert!(pending.contains_key("52:54:00:00:00:00"));
+        assert!(pending.contains_key("52:54:00:94:9e:f2"));
+    }
+
+    #[test]
+    fn test_persisted() {
+        let path = std::env::temp_dir().
+            join(format!("rustboot-state-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let store = StateStore::open(Some(path)).unwrap();
+        store.set(&mac(), Some(BootState::Install)).unwrap();
+        let reopened = StateStore::open(Some(path)).unwrap();
+        assert_eq!(Some(BootState::Install), reopened.get(&mac()).state);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Ok(BootState::Localboot), "installed".parse());
+        assert_eq!("rescue", BootState::Rescue.to_string());
+        assert!("reinstall".parse::<BootState>().is_err());
+    }
+}
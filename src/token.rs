@@ -0,0 +1,132 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::SystemTime;
+
+use hmac::{Hmac, Mac};
+use mac_address::MacAddress;
+use sha2::Sha256;
+
+use crate::config::mac_path;
+use crate::config::TokensConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const KEY_LEN: usize = 32;
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).
+        map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// Signs and checks the tokens in boot and installer URLs.  A token is
+/// good until it expires, for one MAC address and, if it names one, for
+/// requests from one IP address.  It has the form
+/// <expires>-<ip>-<signature>, where the IP address may be empty.
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl Default for TokenSigner {
+    /// A signer with a fresh random key
+    fn default() -> TokenSigner {
+        TokenSigner::generate().expect("no random numbers for a token key")
+    }
+}
+
+impl TokenSigner {
+    pub fn new(key: &[u8]) -> TokenSigner {
+        TokenSigner { key: key.to_vec() }
+    }
+
+    pub fn generate() -> Result<TokenSigner, Error> {
+        let mut key = vec![0; KEY_LEN];
+        getrandom::getrandom(&mut key).map_err(|e| Error::other(e.to_string()))?;
+        Ok(TokenSigner { key })
+    }
+
+    /// The key in the key file, or a random one.  Tokens signed with a
+    /// random key are no good after a restart.
+    pub fn from_config(config: &TokensConfig) -> Result<TokenSigner, Error> {
+        match &config.key_file {
+            Some(key_file) => {
+                let key = fs::read(key_file)?;
+                if key.len() < KEY_LEN / 2 {
+                    return Err(Error::new(ErrorKind::InvalidData, "token key is too short"));
+                }
+                Ok(TokenSigner::new(&key))
+            },
+            None => TokenSigner::generate()
+        }
+    }
+
+    fn mac(&self, mac: &MacAddress, ip: &str, expires: u64) -> HmacSha256 {
+        let mut hmac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes any key length");
+        hmac.update(format!("{}|{}|{}", mac_path(mac), ip, expires).as_bytes());
+        hmac
+    }
+
+    pub fn sign(&self, mac: &MacAddress, ip: Option<Ipv4Addr>, expires: u64) -> String {
+        let ip = ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let signature = self.mac(mac, &ip, expires).finalize().into_bytes();
+        let signature: String = signature.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}-{}-{}", expires, ip, signature)
+    }
+
+    pub fn verify(&self, token: &str, mac: &MacAddress, remote: Option<IpAddr>,
+                  now: u64) -> Result<(), &'static str> {
+        let mut parts = token.splitn(3, '-');
+        let (expires, ip, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(expires), Some(ip), Some(signature)) => (expires, ip, signature),
+            _ => return Err("malformed token")
+        };
+        let expires: u64 = expires.parse().map_err(|_| "malformed token")?;
+        let signature = crate::config::parse_hex(signature).map_err(|_| "malformed token")?;
+        self.mac(mac, ip, expires).verify_slice(&signature).map_err(|_| "bad token signature")?;
+        if now >= expires {
+            return Err("token has expired");
+        }
+        if !ip.is_empty() && remote.map(|remote| remote.to_string()).as_deref() != Some(ip) {
+            return Err("token is for another address");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac() -> MacAddress {
+        MacAddress::new([0x52, 0x54, 0, 0x94, 0x9e, 0xf2])
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = TokenSigner::new(b"0123456789abcdef0123456789abcdef");
+        let token = signer.sign(&mac(), None, 1000);
+        assert!(token.starts_with("1000--"));
+        assert_eq!(Ok(()), signer.verify(&token, &mac(), None, 999));
+        assert_eq!(Err("token has expired"), signer.verify(&token, &mac(), None, 1000));
+        let other = MacAddress::new([0x52, 0x54, 0, 0, 0, 1]);
+        assert_eq!(Err("bad token signature"), signer.verify(&token, &other, None, 999));
+        assert_eq!(Err("bad token signature"),
+                   signer.verify(&token.replace("1000-", "9999-"), &mac(), None, 999));
+        assert_eq!(Err("bad token signature"),
+                   TokenSigner::generate().unwrap().verify(&token, &mac(), None, 999));
+        assert_eq!(Err("malformed token"), signer.verify("1000", &mac(), None, 999));
+    }
+
+    #[test]
+    fn test_bound_to_address() {
+        let signer = TokenSigner::generate().unwrap();
+        let ip = Ipv4Addr::new(10, 0, 0, 5);
+        let token = signer.sign(&mac(), Some(ip), 1000);
+        assert_eq!(Ok(()), signer.verify(&token, &mac(), Some(IpAddr::V4(ip)), 999));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 6));
+        assert_eq!(Err("token is for another address"),
+                   signer.verify(&token, &mac(), Some(other), 999));
+        assert_eq!(Err("token is for another address"), signer.verify(&token, &mac(), None, 999));
+    }
+}